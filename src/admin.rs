@@ -0,0 +1,107 @@
+use crate::iiif::error::IiifError;
+use crate::AppState;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::HeaderMap,
+    response::IntoResponse,
+    routing::delete,
+    Json,
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use std::path::{Component, Path as StdPath};
+use std::sync::Arc;
+
+#[derive(Deserialize)]
+struct PurgeQuery {
+    prefix: String,
+}
+
+#[derive(Serialize)]
+struct PurgeResponse {
+    identifiers: usize,
+    tiles: usize,
+    proxy_files: usize,
+}
+
+/// Cache administration routes, mounted only when `[admin]` is configured
+pub fn router() -> Router<Arc<AppState>> {
+    Router::new()
+        .route("/admin/cache", delete(purge_prefix))
+        .route("/admin/cache/{*identifier}", delete(purge_identifier))
+}
+
+async fn purge_identifier(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Path(identifier): Path<String>,
+) -> Result<impl IntoResponse, IiifError> {
+    authorize(&state, &headers)?;
+    check_identifier(&identifier)?;
+
+    let report = state.cache.purge_identifier(&identifier).await;
+    state.info.purge_identifier(&identifier);
+    let proxy_files = state.resolver.purge_proxy(&identifier).await;
+    tracing::info!("Purged {} tiles for {}", report.tiles, identifier);
+
+    Ok(Json(PurgeResponse {
+        identifiers: report.identifiers,
+        tiles: report.tiles,
+        proxy_files,
+    }))
+}
+
+async fn purge_prefix(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    Query(query): Query<PurgeQuery>,
+) -> Result<impl IntoResponse, IiifError> {
+    authorize(&state, &headers)?;
+    // An empty prefix would match every identifier
+    if query.prefix.is_empty() {
+        return Err(IiifError::InvalidSyntax { segment: "prefix", value: query.prefix });
+    }
+    check_identifier(&query.prefix)?;
+
+    let report = state.cache.purge_prefix(&query.prefix).await;
+    state.info.purge_prefix(&query.prefix);
+    let proxy_files = state.resolver.purge_proxy_prefix(&query.prefix).await;
+    tracing::info!("Purged {} tiles under prefix '{}'", report.tiles, query.prefix);
+
+    Ok(Json(PurgeResponse {
+        identifiers: report.identifiers,
+        tiles: report.tiles,
+        proxy_files,
+    }))
+}
+
+fn authorize(state: &AppState, headers: &HeaderMap) -> Result<(), IiifError> {
+    let Some(admin) = &state.config.admin else {
+        return Err(IiifError::Unauthorized);
+    };
+    let presented = headers
+        .get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .ok_or(IiifError::Unauthorized)?;
+    if !admin.token.is_empty() && constant_time_eq(presented.as_bytes(), admin.token.as_bytes()) {
+        Ok(())
+    } else {
+        Err(IiifError::Unauthorized)
+    }
+}
+
+// Compares without short-circuiting so the token can't be guessed byte by byte
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+// Identifiers map onto proxy paths, so refuse anything that walks out of them
+// (`..`) or is joined as an absolute path (a leading `/`)
+fn check_identifier(identifier: &str) -> Result<(), IiifError> {
+    if StdPath::new(identifier).components().any(|c| !matches!(c, Component::Normal(_))) {
+        return Err(IiifError::InvalidSyntax { segment: "identifier", value: identifier.to_string() });
+    }
+    Ok(())
+}
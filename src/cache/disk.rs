@@ -0,0 +1,261 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+// In-progress writes use this suffix until they are renamed into place
+pub const TEMP_SUFFIX: &str = ".tmp";
+
+/// Location of `key` in the two-level sharded layout, e.g. `ab/cd/abcd…`
+pub fn shard_path(dir: &Path, key: &str) -> PathBuf {
+    match (key.get(0..2), key.get(2..4)) {
+        (Some(a), Some(b)) => dir.join(a).join(b).join(key),
+        _ => dir.join(key),
+    }
+}
+
+/// Moves tiles left at the top level by the old flat layout into their
+/// shard directories. Runs once at startup, before the index is built.
+pub fn migrate_flat_layout(dir: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    let mut migrated = 0usize;
+    for entry in entries.flatten() {
+        let is_file = entry.file_type().is_ok_and(|t| t.is_file());
+        let Some(key) = entry.file_name().to_str().map(str::to_string) else {
+            continue;
+        };
+        if !is_file || !is_cache_key(&key) {
+            continue;
+        }
+        let dest = shard_path(dir, &key);
+        let moved = dest
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| fs::rename(entry.path(), &dest));
+        match moved {
+            Ok(()) => migrated += 1,
+            Err(e) => tracing::warn!("Failed to migrate cached tile {}: {}", key, e),
+        }
+    }
+    if migrated > 0 {
+        tracing::info!("Migrated {} cached tiles to the sharded layout", migrated);
+    }
+}
+
+fn is_cache_key(name: &str) -> bool {
+    name.len() == 64 && name.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Tracks the size and recency of every tile in the disk cache so the
+/// directory can be kept under `cache.disk_limit` by evicting the least
+/// recently used tiles first.
+pub struct DiskIndex {
+    state: Mutex<IndexState>,
+    limit: u64,
+}
+
+#[derive(Default)]
+struct IndexState {
+    entries: HashMap<String, IndexEntry>,
+    // Access tick -> key, oldest first
+    lru: BTreeMap<u64, String>,
+    clock: u64,
+    total_bytes: u64,
+}
+
+struct IndexEntry {
+    size: u64,
+    tick: u64,
+}
+
+impl IndexState {
+    fn remove(&mut self, key: &str) -> Option<IndexEntry> {
+        let entry = self.entries.remove(key)?;
+        self.lru.remove(&entry.tick);
+        self.total_bytes -= entry.size;
+        Some(entry)
+    }
+
+    fn insert(&mut self, key: &str, size: u64) {
+        self.remove(key);
+        self.clock += 1;
+        self.lru.insert(self.clock, key.to_string());
+        self.entries.insert(key.to_string(), IndexEntry { size, tick: self.clock });
+        self.total_bytes += size;
+    }
+
+    fn evict_to(&mut self, limit: u64) -> Vec<String> {
+        let mut evicted = Vec::new();
+        while self.total_bytes > limit {
+            let Some((_, key)) = self.lru.pop_first() else {
+                break;
+            };
+            if let Some(entry) = self.entries.remove(&key) {
+                self.total_bytes -= entry.size;
+            }
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+impl DiskIndex {
+    /// Rebuilds the index from the files already in `dir`, ordering them by
+    /// modification time so the oldest tiles are evicted first.
+    pub fn load(dir: &Path, limit: u64) -> Self {
+        let mut files = Vec::new();
+        scan_dir(dir, &mut files);
+        files.sort_by_key(|(_, _, modified)| *modified);
+
+        let mut state = IndexState::default();
+        for (key, size, _) in files {
+            state.insert(&key, size);
+        }
+        tracing::info!(
+            "Disk cache index loaded: {} tiles, {} bytes (limit {})",
+            state.entries.len(),
+            state.total_bytes,
+            limit
+        );
+
+        Self {
+            state: Mutex::new(state),
+            limit,
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.state.lock().unwrap().entries.contains_key(key)
+    }
+
+    /// Marks `key` as recently used; returns false if it is not indexed.
+    pub fn touch(&self, key: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let Some(size) = state.entries.get(key).map(|e| e.size) else {
+            return false;
+        };
+        state.insert(key, size);
+        true
+    }
+
+    /// Records a newly written tile and returns the keys that must be
+    /// deleted to stay within the limit.
+    pub fn insert(&self, key: &str, size: u64) -> Vec<String> {
+        let mut state = self.state.lock().unwrap();
+        state.insert(key, size);
+        state.evict_to(self.limit)
+    }
+
+    /// Returns the keys that must be deleted to get back under the limit,
+    /// e.g. after the limit was lowered between restarts.
+    pub fn evict(&self) -> Vec<String> {
+        self.state.lock().unwrap().evict_to(self.limit)
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.state.lock().unwrap().remove(key);
+    }
+
+    /// Current (bytes, entries) held on disk
+    pub fn usage(&self) -> (u64, usize) {
+        let state = self.state.lock().unwrap();
+        (state.total_bytes, state.entries.len())
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+}
+
+fn scan_dir(dir: &Path, files: &mut Vec<(String, u64, SystemTime)>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let Ok(meta) = entry.metadata() else {
+            continue;
+        };
+        if meta.is_dir() {
+            scan_dir(&path, files);
+        } else if let Some(name) = path.file_name().and_then(|n| n.to_str()) {
+            // Leftovers from writes interrupted by a crash
+            if name.ends_with(TEMP_SUFFIX) {
+                let _ = fs::remove_file(&path);
+                continue;
+            }
+            if !is_cache_key(name) {
+                continue;
+            }
+            let modified = meta.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+            files.push((name.to_string(), meta.len(), modified));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(limit: u64) -> DiskIndex {
+        DiskIndex { state: Mutex::new(IndexState::default()), limit }
+    }
+
+    #[test]
+    fn evicts_least_recently_inserted_first() {
+        let index = index(300);
+        assert!(index.insert("a", 100).is_empty());
+        assert!(index.insert("b", 100).is_empty());
+        assert!(index.insert("c", 100).is_empty());
+        assert_eq!(index.insert("d", 150), vec!["a", "b"]);
+        assert_eq!(index.usage(), (250, 2));
+        assert!(!index.contains("a"));
+        assert!(index.contains("d"));
+    }
+
+    #[test]
+    fn touch_moves_entry_to_the_back() {
+        let index = index(300);
+        index.insert("a", 100);
+        index.insert("b", 100);
+        index.insert("c", 100);
+        assert!(index.touch("a"));
+        assert_eq!(index.insert("d", 100), vec!["b"]);
+        assert!(index.contains("a"));
+    }
+
+    #[test]
+    fn touch_reports_unknown_keys() {
+        let index = index(300);
+        assert!(!index.touch("missing"));
+        assert_eq!(index.usage(), (0, 0));
+    }
+
+    #[test]
+    fn reinserting_a_key_replaces_its_size() {
+        let index = index(300);
+        index.insert("a", 100);
+        index.insert("a", 250);
+        assert_eq!(index.usage(), (250, 1));
+    }
+
+    #[test]
+    fn removed_entries_free_their_space() {
+        let index = index(300);
+        index.insert("a", 200);
+        index.remove("a");
+        assert_eq!(index.usage(), (0, 0));
+        assert!(index.insert("b", 300).is_empty());
+    }
+
+    #[test]
+    fn a_tile_larger_than_the_limit_evicts_itself() {
+        let index = index(100);
+        index.insert("a", 50);
+        assert_eq!(index.insert("b", 150), vec!["a", "b"]);
+        assert_eq!(index.usage(), (0, 0));
+    }
+}
@@ -0,0 +1,291 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::JoinHandle;
+
+const JOURNAL_FILE: &str = "identifiers.log";
+
+// The journal is rewritten once it holds this many lines and at least
+// twice as many as there are live keys
+const COMPACT_MIN_LINES: usize = 10_000;
+
+/// Maps identifiers to the opaque cache keys rendered from them, so every
+/// tile of one image can be found and purged. Persisted as an append-only
+/// journal of `<key>\t<identifier>` lines, compacted on startup and again
+/// whenever evictions leave it mostly stale. The journal is written by a
+/// dedicated thread, so callers on the async runtime never block on disk.
+pub struct IdentifierIndex {
+    state: Mutex<IndexState>,
+    writer: Option<JournalWriter>,
+}
+
+#[derive(Default)]
+struct IndexState {
+    keys: HashMap<String, HashSet<String>>,
+    // Key -> identifier, so evicted keys can be dropped without a scan
+    owners: HashMap<String, String>,
+    journal_lines: usize,
+}
+
+impl IndexState {
+    fn insert(&mut self, identifier: &str, key: &str) -> bool {
+        if self.owners.contains_key(key) {
+            return false;
+        }
+        self.keys.entry(identifier.to_string()).or_default().insert(key.to_string());
+        self.owners.insert(key.to_string(), identifier.to_string());
+        true
+    }
+
+    fn remove(&mut self, key: &str) {
+        let Some(identifier) = self.owners.remove(key) else {
+            return;
+        };
+        if let Some(set) = self.keys.get_mut(&identifier) {
+            set.remove(key);
+            if set.is_empty() {
+                self.keys.remove(&identifier);
+            }
+        }
+    }
+
+    fn snapshot(&self) -> String {
+        let mut lines = String::new();
+        for (key, identifier) in &self.owners {
+            lines.push_str(&format!("{}\t{}\n", key, identifier));
+        }
+        lines
+    }
+}
+
+enum JournalOp {
+    Append(String),
+    // Replaces the journal with these lines
+    Rewrite(String),
+}
+
+struct JournalWriter {
+    sender: Sender<JournalOp>,
+    thread: JoinHandle<()>,
+}
+
+impl IdentifierIndex {
+    /// Loads the journal in `dir`, keeping only keys for which `is_live`
+    /// returns true, and rewrites it without the dropped lines.
+    pub fn load(dir: &Path, is_live: impl Fn(&str) -> bool) -> Self {
+        let journal_path = dir.join(JOURNAL_FILE);
+        let mut state = IndexState::default();
+
+        if let Ok(contents) = std::fs::read_to_string(&journal_path) {
+            for line in contents.lines() {
+                if let Some((key, identifier)) = line.split_once('\t')
+                    && is_live(key)
+                {
+                    state.insert(identifier, key);
+                }
+            }
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        let thread = std::thread::Builder::new()
+            .name("identifier-journal".to_string())
+            .spawn(move || write_journal(&journal_path, receiver))
+            .expect("Failed to start the identifier journal writer");
+        let index = Self {
+            state: Mutex::new(state),
+            writer: Some(JournalWriter { sender, thread }),
+        };
+        index.compact(&mut index.state.lock().unwrap());
+        index
+    }
+
+    /// Associates `key` with `identifier`, journaling new associations.
+    pub fn record(&self, identifier: &str, key: &str) {
+        let mut state = self.state.lock().unwrap();
+        if !state.insert(identifier, key) {
+            return;
+        }
+        // Queued while holding the lock so the journal sees operations in order
+        self.send(JournalOp::Append(format!("{}\t{}\n", key, identifier)));
+        state.journal_lines += 1;
+    }
+
+    /// Forgets keys whose tiles have been evicted from the cache.
+    pub fn remove<'a>(&self, keys: impl IntoIterator<Item = &'a String>) {
+        let mut state = self.state.lock().unwrap();
+        for key in keys {
+            state.remove(key);
+        }
+        self.compact_if_stale(&mut state);
+    }
+
+    /// Removes and returns the keys of every identifier matched by `matches`,
+    /// along with the number of identifiers matched.
+    pub fn take_matching(&self, matches: impl Fn(&str) -> bool) -> (usize, Vec<String>) {
+        let mut state = self.state.lock().unwrap();
+        let identifiers: Vec<String> = state.keys.keys().filter(|id| matches(id)).cloned().collect();
+        let taken: Vec<String> = identifiers
+            .iter()
+            .filter_map(|id| state.keys.remove(id))
+            .flatten()
+            .collect();
+        for key in &taken {
+            state.owners.remove(key);
+        }
+        self.compact_if_stale(&mut state);
+        (identifiers.len(), taken)
+    }
+
+    fn compact_if_stale(&self, state: &mut IndexState) {
+        if state.journal_lines >= COMPACT_MIN_LINES && state.journal_lines >= 2 * state.owners.len() {
+            self.compact(state);
+        }
+    }
+
+    fn compact(&self, state: &mut IndexState) {
+        self.send(JournalOp::Rewrite(state.snapshot()));
+        state.journal_lines = state.owners.len();
+    }
+
+    fn send(&self, op: JournalOp) {
+        if let Some(writer) = &self.writer
+            && writer.sender.send(op).is_err()
+        {
+            tracing::warn!("Cache identifier journal writer has stopped");
+        }
+    }
+}
+
+// Flushes queued operations before returning, so nothing recorded is lost on shutdown
+impl Drop for IdentifierIndex {
+    fn drop(&mut self) {
+        if let Some(JournalWriter { sender, thread }) = self.writer.take() {
+            drop(sender);
+            let _ = thread.join();
+        }
+    }
+}
+
+// Runs until the index is dropped, batching appends between flushes
+fn write_journal(path: &Path, receiver: Receiver<JournalOp>) {
+    let mut file: Option<BufWriter<File>> = None;
+    while let Ok(op) = receiver.recv() {
+        for op in std::iter::once(op).chain(receiver.try_iter()) {
+            match op {
+                JournalOp::Append(line) => {
+                    if file.is_none() {
+                        file = open_append(path).map(BufWriter::new);
+                    }
+                    if let Some(f) = &mut file
+                        && let Err(e) = f.write_all(line.as_bytes())
+                    {
+                        tracing::warn!("Failed to journal cache key: {}", e);
+                    }
+                }
+                JournalOp::Rewrite(lines) => {
+                    // Pending appends are already part of the snapshot
+                    file = None;
+                    rewrite(path, &lines);
+                }
+            }
+        }
+        if let Some(f) = &mut file
+            && let Err(e) = f.flush()
+        {
+            tracing::warn!("Failed to flush cache identifier journal: {}", e);
+        }
+    }
+}
+
+fn open_append(path: &Path) -> Option<File> {
+    match OpenOptions::new().create(true).append(true).open(path) {
+        Ok(file) => Some(file),
+        Err(e) => {
+            tracing::warn!("Failed to open cache identifier journal: {}", e);
+            None
+        }
+    }
+}
+
+// Writes via a temp file so a crash mid-write never loses the old journal
+fn rewrite(path: &Path, lines: &str) {
+    let temp_path = path.with_extension("log.tmp");
+    let written = std::fs::write(&temp_path, lines).and_then(|_| std::fs::rename(&temp_path, path));
+    if let Err(e) = written {
+        let _ = std::fs::remove_file(&temp_path);
+        tracing::warn!("Failed to compact cache identifier journal: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("iiif-identifiers-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn sorted(mut keys: Vec<String>) -> Vec<String> {
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn takes_keys_of_matching_identifiers() {
+        let index = IdentifierIndex::load(&temp_dir("take"), |_| true);
+        index.record("a/one.tif", "k1");
+        index.record("a/one.tif", "k2");
+        index.record("b/two.tif", "k3");
+
+        let (identifiers, keys) = index.take_matching(|id| id.starts_with("a/"));
+        assert_eq!(identifiers, 1);
+        assert_eq!(sorted(keys), vec!["k1", "k2"]);
+        assert_eq!(index.take_matching(|id| id.starts_with("a/")), (0, vec![]));
+        assert_eq!(index.take_matching(|_| true), (1, vec!["k3".to_string()]));
+    }
+
+    #[test]
+    fn removed_keys_are_no_longer_taken() {
+        let index = IdentifierIndex::load(&temp_dir("remove"), |_| true);
+        index.record("one.tif", "k1");
+        index.record("one.tif", "k2");
+        index.record("two.tif", "k3");
+        index.remove(&["k1".to_string(), "k3".to_string()]);
+
+        assert_eq!(index.take_matching(|_| true), (1, vec!["k2".to_string()]));
+    }
+
+    #[test]
+    fn reload_keeps_only_live_keys() {
+        let dir = temp_dir("reload");
+        {
+            let index = IdentifierIndex::load(&dir, |_| true);
+            index.record("one.tif", "k1");
+            index.record("one.tif", "k2");
+            index.record("two.tif", "k3");
+            index.remove(&["k2".to_string()]);
+        }
+
+        // As on startup, where only keys still on disk are live
+        let index = IdentifierIndex::load(&dir, |key| key == "k1");
+        let (identifiers, keys) = index.take_matching(|_| true);
+        assert_eq!((identifiers, sorted(keys)), (1, vec!["k1".to_string()]));
+    }
+
+    #[test]
+    fn load_compacts_the_journal() {
+        let dir = temp_dir("compact");
+        std::fs::write(dir.join(JOURNAL_FILE), "k1\tone.tif\nk2\tone.tif\nbroken line\n").unwrap();
+        drop(IdentifierIndex::load(&dir, |key| key == "k1"));
+
+        let journal = std::fs::read_to_string(dir.join(JOURNAL_FILE)).unwrap();
+        assert_eq!(journal, "k1\tone.tif\n");
+    }
+}
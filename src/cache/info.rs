@@ -0,0 +1,67 @@
+use moka::future::Cache;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::iiif::info::LinkingProperties;
+use crate::processor::ImageMetadata;
+
+// Entries are a few hundred bytes, so bound the cache by count
+const MAX_ENTRIES: u64 = 10_000;
+
+/// Everything info.json and render planning need to know about a source,
+/// read once and reused until the source changes
+#[derive(Debug)]
+pub struct SourceInfo {
+    /// Source version (mtime and size) the entry was read from
+    pub version: String,
+    pub metadata: ImageMetadata,
+    pub linking: LinkingProperties,
+}
+
+/// Per-identifier cache of source metadata. Entries are keyed by identifier
+/// and discarded when the source version changes; the TTL bounds how long
+/// edits to sidecar metadata files take to show up.
+pub struct InfoCache {
+    entries: Cache<String, Arc<SourceInfo>>,
+}
+
+impl InfoCache {
+    pub fn new(ttl: Duration) -> Self {
+        let entries = Cache::builder()
+            .max_capacity(MAX_ENTRIES)
+            .time_to_live(ttl)
+            .support_invalidation_closures()
+            .build();
+        Self { entries }
+    }
+
+    /// Cached info for `identifier`, if it was read from the same `version`
+    pub async fn get(&self, identifier: &str, version: &str) -> Option<Arc<SourceInfo>> {
+        self.entries
+            .get(identifier)
+            .await
+            .filter(|info| info.version == version)
+    }
+
+    pub async fn insert(&self, identifier: &str, info: Arc<SourceInfo>) {
+        self.entries.insert(identifier.to_string(), info).await;
+    }
+
+    /// Drops `identifier` and its PDF pages (`identifier:page:N`)
+    pub fn purge_identifier(&self, identifier: &str) {
+        let page_prefix = format!("{}:page:", identifier);
+        let identifier = identifier.to_string();
+        self.purge_matching(move |id| *id == identifier || id.starts_with(&page_prefix));
+    }
+
+    /// Drops every identifier starting with `prefix`
+    pub fn purge_prefix(&self, prefix: &str) {
+        let prefix = prefix.to_string();
+        self.purge_matching(move |id| id.starts_with(&prefix));
+    }
+
+    fn purge_matching(&self, matches: impl Fn(&String) -> bool + Send + Sync + 'static) {
+        if let Err(e) = self.entries.invalidate_entries_if(move |id, _| matches(id)) {
+            tracing::warn!("Failed to purge info cache: {}", e);
+        }
+    }
+}
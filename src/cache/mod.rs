@@ -1,60 +1,268 @@
+pub mod disk;
+pub mod identifiers;
+pub mod info;
+pub mod singleflight;
+
 use moka::future::Cache;
 use tokio::fs;
+use std::future::Future;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use serde::Serialize;
 use sha2::{Sha256, Digest};
+use crate::iiif::error::IiifError;
+use disk::{DiskIndex, TEMP_SUFFIX};
+use identifiers::IdentifierIndex;
+use singleflight::SingleFlight;
 
 pub struct TileCache {
     memory: Cache<String, Vec<u8>>,
     disk_dir: PathBuf,
+    disk_index: Arc<DiskIndex>,
+    identifiers: Arc<IdentifierIndex>,
+    renders: SingleFlight<Result<Vec<u8>, IiifError>>,
+    counters: Arc<Counters>,
+    memory_limit_bytes: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    memory_hits: AtomicU64,
+    disk_hits: AtomicU64,
+    misses: AtomicU64,
+    memory_evictions: AtomicU64,
+}
+
+/// What a purge removed
+#[derive(Debug, Default, Serialize)]
+pub struct PurgeReport {
+    pub identifiers: usize,
+    pub tiles: usize,
+}
+
+/// Point-in-time cache counters
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub memory_hits: u64,
+    pub disk_hits: u64,
+    pub misses: u64,
+    pub memory_evictions: u64,
+    pub memory_usage_bytes: u64,
+    pub memory_limit_bytes: u64,
+    pub memory_entries: u64,
+    pub coalesced_requests: u64,
+    pub disk_usage_bytes: u64,
+    pub disk_limit_bytes: u64,
+    pub disk_entries: usize,
 }
 
 impl TileCache {
-    pub fn new(disk_dir: String, memory_limit_bytes: u64) -> Self {
+    pub fn new(disk_dir: String, memory_limit_bytes: u64, disk_limit_bytes: u64) -> Self {
+        let disk_dir = PathBuf::from(disk_dir);
+        disk::migrate_flat_layout(&disk_dir);
+        let disk_index = Arc::new(DiskIndex::load(&disk_dir, disk_limit_bytes));
+        for key in disk_index.evict() {
+            let _ = std::fs::remove_file(disk::shard_path(&disk_dir, &key));
+        }
+        let identifiers = Arc::new(IdentifierIndex::load(&disk_dir, |key| disk_index.contains(key)));
+
+        let counters = Arc::new(Counters::default());
+        let eviction_counters = Arc::clone(&counters);
+        let eviction_disk = Arc::clone(&disk_index);
+        let eviction_identifiers = Arc::clone(&identifiers);
+        // Weigh tiles by their encoded size so max_capacity is a byte budget
         let memory = Cache::builder()
             .max_capacity(memory_limit_bytes)
+            .weigher(|_key: &String, data: &Vec<u8>| data.len().try_into().unwrap_or(u32::MAX))
+            .eviction_listener(move |key: Arc<String>, _data, cause| {
+                if cause.was_evicted() {
+                    eviction_counters.memory_evictions.fetch_add(1, Ordering::Relaxed);
+                    // Tiles that never made it to disk are gone for good
+                    if !eviction_disk.contains(&key) {
+                        eviction_identifiers.remove([&*key]);
+                    }
+                }
+            })
             .build();
-        
-        let disk_dir = PathBuf::from(disk_dir);
-        
+
         Self {
             memory,
             disk_dir,
+            disk_index,
+            identifiers,
+            renders: SingleFlight::new(),
+            counters,
+            memory_limit_bytes,
         }
     }
 
-    pub fn get_key(identifier: &str, params: &str) -> String {
+    /// Cache key for a rendering of `identifier` at a given source `version`.
+    /// Fields are NUL-separated so different splits never collide.
+    pub fn get_key(identifier: &str, version: &str, params: &str) -> String {
         let mut hasher = Sha256::new();
         hasher.update(identifier);
+        hasher.update([0]);
+        hasher.update(version);
+        hasher.update([0]);
         hasher.update(params);
         format!("{:x}", hasher.finalize())
     }
 
     pub async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        let data = self.lookup(key).await;
+        if data.is_none() {
+            self.counters.misses.fetch_add(1, Ordering::Relaxed);
+        }
+        data
+    }
+
+    // Memory then disk, counting hits but not misses
+    async fn lookup(&self, key: &str) -> Option<Vec<u8>> {
         // Try Memory
         if let Some(data) = self.memory.get(key).await {
+            // Keep hot tiles from aging out of the disk cache
+            self.disk_index.touch(key);
+            self.counters.memory_hits.fetch_add(1, Ordering::Relaxed);
             return Some(data.to_vec());
         }
 
         // Try Disk
-        let disk_path = self.disk_dir.join(key);
-        if let Ok(data) = fs::read(&disk_path).await {
-            // Populate memory cache
-            self.memory.insert(key.to_string(), data.clone()).await;
-            return Some(data);
+        let disk_path = disk::shard_path(&self.disk_dir, key);
+        match fs::read(&disk_path).await {
+            Ok(data) => {
+                if !self.disk_index.touch(key) {
+                    self.evict(self.disk_index.insert(key, data.len() as u64)).await;
+                }
+                // Populate memory cache
+                self.memory.insert(key.to_string(), data.clone()).await;
+                self.counters.disk_hits.fetch_add(1, Ordering::Relaxed);
+                Some(data)
+            }
+            Err(_) => {
+                self.disk_index.remove(key);
+                None
+            }
         }
-
-        None
     }
 
-    pub async fn set(&self, key: &str, data: Vec<u8>) {
+    pub async fn set(&self, identifier: &str, key: &str, data: Vec<u8>) {
+        // Set Disk first, so a memory eviction can tell whether the tile survives
+        let size = data.len() as u64;
+        match self.write_disk(key, &data).await {
+            Ok(()) => self.evict(self.disk_index.insert(key, size)).await,
+            Err(e) => tracing::warn!("Failed to write tile {} to disk cache: {}", key, e),
+        }
+
+        // Recorded after the disk eviction, which may drop this very key
+        self.identifiers.record(identifier, key);
+
         // Set Memory
-        self.memory.insert(key.to_string(), data.clone()).await;
+        self.memory.insert(key.to_string(), data).await;
+    }
+
+    // Writes to a unique temp file and renames it into place, so readers
+    // never observe a partially written tile
+    async fn write_disk(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        static WRITE_SEQ: AtomicU64 = AtomicU64::new(0);
 
-        // Set Disk
-        let disk_path = self.disk_dir.join(key);
+        let disk_path = disk::shard_path(&self.disk_dir, key);
         if let Some(parent) = disk_path.parent() {
-            let _ = fs::create_dir_all(parent).await;
+            fs::create_dir_all(parent).await?;
+        }
+        let seq = WRITE_SEQ.fetch_add(1, Ordering::Relaxed);
+        let temp_path = disk_path.with_file_name(format!("{}.{}.{}{}", key, std::process::id(), seq, TEMP_SUFFIX));
+        if let Err(e) = fs::write(&temp_path, data).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        if let Err(e) = fs::rename(&temp_path, &disk_path).await {
+            let _ = fs::remove_file(&temp_path).await;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // Drops tiles evicted from disk everywhere, so the identifier index
+    // only ever holds keys that are still cached
+    async fn evict(&self, keys: Vec<String>) {
+        if keys.is_empty() {
+            return;
+        }
+        tracing::debug!("Evicting {} tiles from the disk cache", keys.len());
+        for key in &keys {
+            let _ = fs::remove_file(disk::shard_path(&self.disk_dir, key)).await;
+            self.memory.invalidate(key).await;
+        }
+        self.identifiers.remove(&keys);
+    }
+
+    /// Returns the cached tile for `key`, or runs `render` and caches its
+    /// output. Concurrent misses for the same key share a single render.
+    pub async fn get_or_render<F, Fut>(&self, identifier: &str, key: &str, render: F) -> Result<Vec<u8>, IiifError>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Vec<u8>, IiifError>>,
+    {
+        if let Some(data) = self.get(key).await {
+            tracing::debug!("Cache hit for {}", key);
+            return Ok(data);
+        }
+
+        self.renders
+            .run(key, || async {
+                // A flight that finished after our miss has already cached the tile
+                if let Some(data) = self.lookup(key).await {
+                    return Ok(data);
+                }
+                let data = render().await?;
+                self.set(identifier, key, data.clone()).await;
+                Ok(data)
+            })
+            .await
+    }
+
+    /// Removes every tile rendered from `identifier`, including the pages
+    /// of a PDF (`identifier:page:N`), from memory and disk.
+    pub async fn purge_identifier(&self, identifier: &str) -> PurgeReport {
+        let page_prefix = format!("{}:page:", identifier);
+        self.purge_matching(|id| id == identifier || id.starts_with(&page_prefix)).await
+    }
+
+    /// Removes every tile whose identifier starts with `prefix`.
+    pub async fn purge_prefix(&self, prefix: &str) -> PurgeReport {
+        self.purge_matching(|id| id.starts_with(prefix)).await
+    }
+
+    async fn purge_matching(&self, matches: impl Fn(&str) -> bool) -> PurgeReport {
+        let (identifiers, keys) = self.identifiers.take_matching(matches);
+        let mut tiles = 0;
+        for key in &keys {
+            let in_memory = self.memory.remove(key).await.is_some();
+            let on_disk = self.disk_index.contains(key);
+            self.disk_index.remove(key);
+            let _ = fs::remove_file(disk::shard_path(&self.disk_dir, key)).await;
+            if in_memory || on_disk {
+                tiles += 1;
+            }
+        }
+        PurgeReport { identifiers, tiles }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let (disk_usage_bytes, disk_entries) = self.disk_index.usage();
+        CacheStats {
+            memory_hits: self.counters.memory_hits.load(Ordering::Relaxed),
+            disk_hits: self.counters.disk_hits.load(Ordering::Relaxed),
+            misses: self.counters.misses.load(Ordering::Relaxed),
+            memory_evictions: self.counters.memory_evictions.load(Ordering::Relaxed),
+            memory_usage_bytes: self.memory.weighted_size(),
+            memory_limit_bytes: self.memory_limit_bytes,
+            memory_entries: self.memory.entry_count(),
+            coalesced_requests: self.renders.coalesced(),
+            disk_usage_bytes,
+            disk_limit_bytes: self.disk_index.limit(),
+            disk_entries,
         }
-        let _ = fs::write(disk_path, data).await;
     }
 }
@@ -0,0 +1,140 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::sync::OnceCell;
+
+/// Deduplicates concurrent work by key: the first caller runs the future and
+/// everyone who arrives while it is in flight receives a clone of its result.
+/// If the running caller is cancelled, one of the waiters takes over.
+pub struct SingleFlight<T> {
+    inflight: Mutex<HashMap<String, Arc<OnceCell<T>>>>,
+    coalesced: AtomicU64,
+}
+
+impl<T: Clone> SingleFlight<T> {
+    pub fn new() -> Self {
+        Self {
+            inflight: Mutex::new(HashMap::new()),
+            coalesced: AtomicU64::new(0),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: &str, f: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = T>,
+    {
+        let cell = {
+            let mut inflight = self.inflight.lock().unwrap();
+            match inflight.get(key) {
+                Some(cell) => {
+                    self.coalesced.fetch_add(1, Ordering::Relaxed);
+                    Arc::clone(cell)
+                }
+                None => {
+                    let cell = Arc::new(OnceCell::new());
+                    inflight.insert(key.to_string(), Arc::clone(&cell));
+                    cell
+                }
+            }
+        };
+
+        let _guard = FlightGuard { flight: self, key, cell: &cell };
+        cell.get_or_init(f).await.clone()
+    }
+
+    /// Number of requests that were served by another request's work
+    pub fn coalesced(&self) -> u64 {
+        self.coalesced.load(Ordering::Relaxed)
+    }
+}
+
+// Removes the in-flight entry once its result is available, or when the last
+// interested caller goes away, so later requests go back through the cache
+struct FlightGuard<'a, T> {
+    flight: &'a SingleFlight<T>,
+    key: &'a str,
+    cell: &'a Arc<OnceCell<T>>,
+}
+
+impl<T> Drop for FlightGuard<'_, T> {
+    fn drop(&mut self) {
+        let Ok(mut inflight) = self.flight.inflight.lock() else {
+            return;
+        };
+        let is_current = inflight.get(self.key).is_some_and(|c| Arc::ptr_eq(c, self.cell));
+        // Two references left means only the map and this caller hold it
+        if is_current && (self.cell.initialized() || Arc::strong_count(self.cell) <= 2) {
+            inflight.remove(self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::sync::Notify;
+
+    fn is_idle<T>(flight: &SingleFlight<T>) -> bool {
+        flight.inflight.lock().unwrap().is_empty()
+    }
+
+    #[tokio::test]
+    async fn concurrent_callers_share_one_run() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        let runs = AtomicU64::new(0);
+        let gate = Notify::new();
+        let work = || async {
+            runs.fetch_add(1, Ordering::Relaxed);
+            gate.notified().await;
+            42
+        };
+
+        let (a, b, c, _) = tokio::join!(
+            flight.run("tile", work),
+            flight.run("tile", work),
+            flight.run("tile", work),
+            async { gate.notify_one() },
+        );
+        assert_eq!((a, b, c), (42, 42, 42));
+        assert_eq!(runs.load(Ordering::Relaxed), 1);
+        assert_eq!(flight.coalesced(), 2);
+    }
+
+    #[tokio::test]
+    async fn finished_flights_are_removed() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        assert_eq!(flight.run("tile", || async { 1 }).await, 1);
+        assert!(is_idle(&flight));
+        // A later call runs again instead of reusing the old result
+        assert_eq!(flight.run("tile", || async { 2 }).await, 2);
+        assert_eq!(flight.coalesced(), 0);
+    }
+
+    #[tokio::test]
+    async fn waiter_takes_over_from_a_cancelled_leader() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        let (leader, waiter) = tokio::join!(
+            tokio::time::timeout(Duration::from_millis(20), flight.run("tile", std::future::pending)),
+            async {
+                tokio::task::yield_now().await;
+                flight.run("tile", || async { 7 }).await
+            },
+        );
+        assert!(leader.is_err());
+        assert_eq!(waiter, 7);
+        assert_eq!(flight.coalesced(), 1);
+        assert!(is_idle(&flight));
+    }
+
+    #[tokio::test]
+    async fn cancelled_flight_without_waiters_is_removed() {
+        let flight: SingleFlight<u32> = SingleFlight::new();
+        let leader = tokio::time::timeout(Duration::from_millis(1), flight.run("tile", std::future::pending)).await;
+        assert!(leader.is_err());
+        assert!(is_idle(&flight));
+        assert_eq!(flight.run("tile", || async { 3 }).await, 3);
+    }
+}
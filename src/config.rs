@@ -1,11 +1,22 @@
 use serde::Deserialize;
+use crate::iiif::types::SizeLimits;
+use libvips_rs::ops;
+use std::collections::BTreeMap;
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct Config {
     pub server: ServerConfig,
     pub iiif: IiifConfig,
     pub cache: CacheConfig,
+    pub processing: ProcessingConfig,
+    pub encoding: EncodingConfig,
+    pub quality: QualityConfig,
+    #[serde(default)]
+    pub qualities: BTreeMap<String, Vec<QualityStep>>, // custom qualities by name
+    #[serde(default)]
+    pub watermarks: Vec<WatermarkConfig>,
     pub remote: Option<RemoteConfig>,
+    pub admin: Option<AdminConfig>,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -18,14 +29,286 @@ pub struct ServerConfig {
 pub struct IiifConfig {
     pub source_dir: String,
     pub base_url: String,
+    pub tile_size: u32,
+    #[serde(default)]
+    pub preferred_formats: Vec<String>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_area: Option<u64>, // defaults to 100 megapixels
 }
 
 #[derive(Debug, Deserialize, Clone)]
 pub struct CacheConfig {
     pub memory_limit: String, // e.g., "512MB"
     pub disk_cache_dir: String,
-    #[allow(dead_code)]
     pub disk_limit: String, // e.g., "10GB"
+    pub info_ttl_secs: u64, // how long image metadata and info.json stay cached
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct ProcessingConfig {
+    pub workers: Option<usize>, // defaults to the number of CPUs
+    pub queue_limit: usize, // requests waiting for a worker before 503s
+    pub timeout_secs: u64,
+}
+
+/// Encoder options applied to every image, with optional overrides for
+/// identifiers under a prefix. Unset options keep the libvips defaults.
+#[derive(Debug, Deserialize, Clone)]
+pub struct EncodingConfig {
+    pub output_profile: String, // libvips built-in name ("srgb", "p3") or ICC file path
+    pub embed_profile: bool,
+    pub strip_metadata: bool, // drop EXIF, XMP and IPTC from derivatives
+    pub background: Vec<f64>, // alpha is flattened onto this for JPEG and PDF
+    pub display_bands: Vec<u32>, // bands shown as RGB for images with more than 4
+    #[serde(flatten)]
+    pub defaults: EncodingOptions,
+    #[serde(default)]
+    pub collections: Vec<CollectionEncoding>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CollectionEncoding {
+    pub prefix: String, // e.g., "manuscripts/"
+    #[serde(flatten)]
+    pub options: EncodingOptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct EncodingOptions {
+    #[serde(default)]
+    pub jpeg: JpegOptions,
+    #[serde(default)]
+    pub png: PngOptions,
+    #[serde(default)]
+    pub webp: WebpOptions,
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct JpegOptions {
+    pub quality: Option<i32>, // 1-100
+    pub progressive: Option<bool>,
+    pub subsampling: Option<Subsampling>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Subsampling {
+    Auto, // 4:2:0 unless quality is 90 or higher
+    On,   // always 4:2:0
+    Off,  // always 4:4:4
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct PngOptions {
+    pub compression: Option<i32>, // 0-9
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct WebpOptions {
+    pub quality: Option<i32>, // 1-100
+    pub lossless: Option<bool>,
+}
+
+impl EncodingConfig {
+    // Both are used as RGB triples by flatten, rotate and bandjoin
+    fn validate(&self) -> Result<(), String> {
+        if self.background.len() != 3 || self.background.iter().any(|v| !(0.0..=255.0).contains(v)) {
+            return Err(format!("encoding: background must be three values from 0 to 255, got {:?}", self.background));
+        }
+        if self.display_bands.len() != 3 {
+            return Err(format!("encoding: display_bands must list three bands, got {:?}", self.display_bands));
+        }
+        Ok(())
+    }
+
+    /// Which source metadata the savers copy into derivatives. Output in a
+    /// profile other than sRGB always carries it, as browsers would otherwise
+    /// read the pixels as sRGB.
+    pub fn keep(&self) -> ops::ForeignKeep {
+        let needs_profile = self.embed_profile || !self.output_profile.eq_ignore_ascii_case("srgb");
+        match (self.strip_metadata, needs_profile) {
+            (false, _) => ops::ForeignKeep::All,
+            (true, true) => ops::ForeignKeep::Icc,
+            (true, false) => ops::ForeignKeep::None,
+        }
+    }
+}
+
+impl EncodingOptions {
+    fn validate(&self, section: &str) -> Result<(), String> {
+        check_range(section, "jpeg.quality", self.jpeg.quality, 1, 100)?;
+        check_range(section, "png.compression", self.png.compression, 0, 9)?;
+        check_range(section, "webp.quality", self.webp.quality, 1, 100)
+    }
+
+    // Options set in `other` replace those in `self`
+    fn merge(&mut self, other: &EncodingOptions) {
+        self.jpeg.quality = other.jpeg.quality.or(self.jpeg.quality);
+        self.jpeg.progressive = other.jpeg.progressive.or(self.jpeg.progressive);
+        self.jpeg.subsampling = other.jpeg.subsampling.or(self.jpeg.subsampling);
+        self.png.compression = other.png.compression.or(self.png.compression);
+        self.webp.quality = other.webp.quality.or(self.webp.quality);
+        self.webp.lossless = other.webp.lossless.or(self.webp.lossless);
+    }
+}
+
+fn check_range(section: &str, name: &str, value: Option<i32>, min: i32, max: i32) -> Result<(), String> {
+    match value {
+        Some(v) if !(min..=max).contains(&v) => {
+            Err(format!("{}: {} must be between {} and {}, got {}", section, name, min, max, v))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// How the `gray` and `bitonal` qualities are rendered
+#[derive(Debug, Deserialize, Clone)]
+pub struct QualityConfig {
+    pub bitonal: BitonalMethod,
+    pub threshold: f64, // 0-255 cut-off for "threshold"
+    pub adaptive_radius: f64, // blur sigma of the local mean for "adaptive"
+    pub adaptive_offset: f64, // how much darker than its surroundings a pixel must be to turn black
+}
+
+impl QualityConfig {
+    fn validate(&self) -> Result<(), String> {
+        if !(0.0..=255.0).contains(&self.threshold) {
+            return Err(format!("quality: threshold must be between 0 and 255, got {}", self.threshold));
+        }
+        // Used as a gaussblur sigma
+        if !(self.adaptive_radius.is_finite() && self.adaptive_radius > 0.0) {
+            return Err(format!("quality: adaptive_radius must be above 0, got {}", self.adaptive_radius));
+        }
+        if !(-255.0..=255.0).contains(&self.adaptive_offset) {
+            return Err(format!("quality: adaptive_offset must be between -255 and 255, got {}", self.adaptive_offset));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BitonalMethod {
+    Threshold, // fixed cut-off
+    Otsu,      // cut-off chosen from each image's histogram
+    Adaptive,  // local thresholding against the neighbourhood mean
+    Dither,    // ordered dithering
+}
+
+/// One operation in a custom quality, written as `"name"` or `"name:value"`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(try_from = "String")]
+pub enum QualityStep {
+    Normalize,  // stretch contrast to the full range
+    Equalize,   // histogram equalisation
+    Sharpen,
+    Invert,
+    Gray,
+    Gamma(f64), // output = input^exponent on 0-1; below 1 brightens
+    Blur(f64),  // gaussian sigma
+}
+
+impl TryFrom<String> for QualityStep {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        let (name, value) = match s.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (s.as_str(), None),
+        };
+        let number = || {
+            value
+                .and_then(|v| v.parse::<f64>().ok())
+                .filter(|v| v.is_finite() && *v > 0.0)
+                .ok_or_else(|| format!("quality step '{}' needs a positive number, e.g. '{}:1.5'", s, name))
+        };
+        match (name, value) {
+            ("normalize", None) => Ok(QualityStep::Normalize),
+            ("equalize", None) => Ok(QualityStep::Equalize),
+            ("sharpen", None) => Ok(QualityStep::Sharpen),
+            ("invert", None) => Ok(QualityStep::Invert),
+            ("gray", None) => Ok(QualityStep::Gray),
+            ("gamma", _) => Ok(QualityStep::Gamma(number()?)),
+            ("blur", _) => Ok(QualityStep::Blur(number()?)),
+            _ => Err(format!("unknown quality step '{}'", s)),
+        }
+    }
+}
+
+/// An image or text overlay for identifiers under `prefix`, applied when
+/// the full image at the requested scale exceeds `min_size` in either dimension
+#[derive(Debug, Deserialize, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct WatermarkConfig {
+    pub prefix: String, // "" applies to every identifier
+    pub min_size: u32,
+    pub image: Option<String>, // path to an overlay image, preferably PNG with alpha
+    pub text: Option<String>, // used when no image is set
+    #[serde(default = "default_watermark_font")]
+    pub font: String, // Pango font description
+    #[serde(default = "default_watermark_color")]
+    pub color: String, // text colour, e.g. "#ffffff"
+    #[serde(default = "default_watermark_opacity")]
+    pub opacity: f64, // 0.0-1.0
+    #[serde(default = "default_watermark_scale")]
+    pub scale: f64, // overlay width as a fraction of the output width
+    #[serde(default)]
+    pub position: WatermarkPosition,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum WatermarkPosition {
+    #[default]
+    Center,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+fn default_watermark_font() -> String {
+    "sans bold 48".to_string()
+}
+
+fn default_watermark_color() -> String {
+    "#ffffff".to_string()
+}
+
+fn default_watermark_opacity() -> f64 {
+    0.5
+}
+
+fn default_watermark_scale() -> f64 {
+    0.3
+}
+
+impl WatermarkConfig {
+    // A policy that can't be rendered must not silently serve unmarked images
+    fn validate(&self) -> Result<(), String> {
+        let section = format!("watermarks (prefix \"{}\")", self.prefix);
+        match (&self.image, &self.text) {
+            (Some(path), _) if !std::path::Path::new(path).is_file() => {
+                return Err(format!("{}: image '{}' does not exist", section, path));
+            }
+            (None, None) => return Err(format!("{}: needs an image or text", section)),
+            (None, Some(text)) if text.trim().is_empty() => return Err(format!("{}: text is empty", section)),
+            _ => {}
+        }
+        if !(0.0..=1.0).contains(&self.opacity) {
+            return Err(format!("{}: opacity must be between 0 and 1, got {}", section, self.opacity));
+        }
+        if !(self.scale > 0.0 && self.scale <= 1.0) {
+            return Err(format!("{}: scale must be above 0 and at most 1, got {}", section, self.scale));
+        }
+        Ok(())
+    }
+
+    /// Distinguishes cached renders made under different watermark settings
+    pub fn cache_tag(&self) -> String {
+        format!("{:?}", self)
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -34,6 +317,11 @@ pub struct RemoteConfig {
     pub local_proxy_dir: String, // where to save downloaded remote files
 }
 
+#[derive(Debug, Deserialize, Clone)]
+pub struct AdminConfig {
+    pub token: String, // required as "Authorization: Bearer <token>"
+}
+
 impl Config {
     pub fn load() -> Result<Self, config::ConfigError> {
         let settings = config::Config::builder()
@@ -43,17 +331,106 @@ impl Config {
             .set_default("server.host", "0.0.0.0")?
             .set_default("iiif.source_dir", "./images")?
             .set_default("iiif.base_url", "http://localhost:8080/iiif/3/")?
+            .set_default("iiif.tile_size", 512)?
+            // Bounds every output, so "^" upscaling can't request arbitrarily large renders
+            .set_default("iiif.max_area", 100_000_000)?
             .set_default("cache.memory_limit", "512MB")?
             .set_default("cache.disk_cache_dir", "./cache")?
             .set_default("cache.disk_limit", "10GB")?
+            .set_default("cache.info_ttl_secs", 300)?
+            .set_default("processing.queue_limit", 64)?
+            .set_default("processing.timeout_secs", 30)?
+            .set_default("encoding.output_profile", "srgb")?
+            .set_default("encoding.embed_profile", false)?
+            .set_default("encoding.strip_metadata", true)?
+            .set_default("encoding.background", vec![255, 255, 255])?
+            .set_default("encoding.display_bands", vec![0, 1, 2])?
+            .set_default("quality.bitonal", "threshold")?
+            .set_default("quality.threshold", 128)?
+            .set_default("quality.adaptive_radius", 15)?
+            .set_default("quality.adaptive_offset", 10)?
             .build()?;
 
-        settings.try_deserialize()
+        let config: Config = settings.try_deserialize()?;
+        config.validate().map_err(config::ConfigError::Message)?;
+        Ok(config)
+    }
+
+    // Catches settings the savers would only reject at render time
+    fn validate(&self) -> Result<(), String> {
+        self.encoding.validate()?;
+        self.quality.validate()?;
+        self.encoding.defaults.validate("encoding")?;
+        for collection in &self.encoding.collections {
+            collection
+                .options
+                .validate(&format!("encoding.collections (prefix \"{}\")", collection.prefix))?;
+        }
+        // Requested as `<name>.<format>`, so the name has to survive that split
+        for name in self.qualities.keys() {
+            if name.is_empty() || name.contains(['.', '/']) || name.chars().any(|c| c.is_uppercase()) {
+                return Err(format!("qualities: '{}' must be lowercase and contain no '.' or '/'", name));
+            }
+        }
+        for watermark in &self.watermarks {
+            watermark.validate()?;
+        }
+        // An empty token would match a request without credentials
+        if self.admin.as_ref().is_some_and(|admin| admin.token.trim().is_empty()) {
+            return Err("admin: token must not be empty".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn size_limits(&self) -> SizeLimits {
+        SizeLimits {
+            max_width: self.iiif.max_width,
+            max_height: self.iiif.max_height,
+            max_area: self.iiif.max_area,
+        }
+    }
+
+    /// Encoder options for `identifier`: the global `[encoding]` options
+    /// overlaid with every matching collection, shortest prefix first, so
+    /// the most specific collection wins.
+    pub fn encoding_for(&self, identifier: &str) -> EncodingOptions {
+        let mut collections: Vec<&CollectionEncoding> = self
+            .encoding
+            .collections
+            .iter()
+            .filter(|c| identifier.starts_with(&c.prefix))
+            .collect();
+        collections.sort_by_key(|c| c.prefix.len());
+
+        let mut options = self.encoding.defaults.clone();
+        for collection in collections {
+            options.merge(&collection.options);
+        }
+        options
+    }
+
+    /// The watermark policy for `identifier`, taken from the matching entry
+    /// with the longest prefix
+    pub fn watermark_for(&self, identifier: &str) -> Option<&WatermarkConfig> {
+        self.watermarks
+            .iter()
+            .filter(|w| identifier.starts_with(&w.prefix))
+            .max_by_key(|w| w.prefix.len())
+    }
+
+    pub fn worker_count(&self) -> usize {
+        self.processing
+            .workers
+            .unwrap_or_else(|| std::thread::available_parallelism().map_or(4, |n| n.get()))
     }
 
     pub fn parse_memory_limit(&self) -> u64 {
         parse_size_string(&self.cache.memory_limit).unwrap_or(512 * 1024 * 1024)
     }
+
+    pub fn parse_disk_limit(&self) -> u64 {
+        parse_size_string(&self.cache.disk_limit).unwrap_or(10 * 1024 * 1024 * 1024)
+    }
 }
 
 fn parse_size_string(s: &str) -> Option<u64> {
@@ -70,3 +447,30 @@ fn parse_size_string(s: &str) -> Option<u64> {
         s.parse::<u64>().ok()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(s: &str) -> Result<QualityStep, String> {
+        QualityStep::try_from(s.to_string())
+    }
+
+    #[test]
+    fn parses_quality_steps() {
+        assert_eq!(step("normalize"), Ok(QualityStep::Normalize));
+        assert_eq!(step("equalize"), Ok(QualityStep::Equalize));
+        assert_eq!(step("sharpen"), Ok(QualityStep::Sharpen));
+        assert_eq!(step("invert"), Ok(QualityStep::Invert));
+        assert_eq!(step("gray"), Ok(QualityStep::Gray));
+        assert_eq!(step("gamma:0.8"), Ok(QualityStep::Gamma(0.8)));
+        assert_eq!(step("blur:2"), Ok(QualityStep::Blur(2.0)));
+    }
+
+    #[test]
+    fn rejects_bad_quality_steps() {
+        for input in ["", "emboss", "Normalize", "sharpen:2", "gamma", "gamma:", "gamma:0", "gamma:-1", "blur:abc", "blur:inf"] {
+            assert!(step(input).is_err(), "{input}");
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Single source of truth for what the server supports. The parser accepts
+//! only what is listed here and info.json is derived from the same tables,
+//! so the advertised capabilities cannot drift from the implementation.
+//! Formats whose codec is missing from the linked libvips are left out, and
+//! custom qualities from the config are registered here at startup.
+
+use crate::iiif::types::{Format, Quality};
+use crate::processor::codecs;
+use std::sync::OnceLock;
+
+static CUSTOM_QUALITIES: OnceLock<Vec<String>> = OnceLock::new();
+
+pub struct Capability<T: 'static> {
+    pub value: T,
+    pub name: &'static str,
+}
+
+pub const FORMATS: &[Capability<Format>] = &[
+    Capability { value: Format::Jpg, name: "jpg" },
+    Capability { value: Format::Png, name: "png" },
+    Capability { value: Format::Tif, name: "tif" },
+    Capability { value: Format::Gif, name: "gif" },
+    Capability { value: Format::Pdf, name: "pdf" },
+    Capability { value: Format::Webp, name: "webp" },
+    Capability { value: Format::Avif, name: "avif" },
+    Capability { value: Format::Jp2, name: "jp2" },
+    Capability { value: Format::Jxl, name: "jxl" },
+];
+
+pub const QUALITIES: &[Capability<Quality>] = &[
+    Capability { value: Quality::Default, name: "default" },
+    Capability { value: Quality::Color, name: "color" },
+    Capability { value: Quality::Gray, name: "gray" },
+    Capability { value: Quality::Bitonal, name: "bitonal" },
+];
+
+pub const FEATURES: &[&str] = &[
+    "baseUriRedirect",
+    "cors",
+    "jsonldMediaType",
+    "regionByPx",
+    "regionSquare",
+    "sizeByW",
+    "sizeByH",
+    "sizeByWh",
+    "regionByPct",
+    "sizeByPct",
+    "sizeByConfinedWh",
+    "rotationBy90s",
+    "mirroring",
+    "rotationArbitrary",
+    "sizeUpscaling",
+];
+
+// Everything the Image API 3.0 compliance document requires at each level
+const REQUIRED_FORMATS: &[(&str, u8)] = &[("jpg", 0), ("png", 2)];
+const REQUIRED_QUALITIES: &[(&str, u8)] = &[("default", 0), ("color", 2), ("gray", 2)];
+const REQUIRED_FEATURES: &[(&str, u8)] = &[
+    ("baseUriRedirect", 1),
+    ("cors", 1),
+    ("jsonldMediaType", 1),
+    ("regionByPx", 1),
+    ("regionSquare", 1),
+    ("sizeByW", 1),
+    ("sizeByH", 1),
+    ("sizeByWh", 1),
+    ("regionByPct", 2),
+    ("sizeByPct", 2),
+    ("sizeByConfinedWh", 2),
+    ("rotationBy90s", 2),
+];
+
+pub fn format_by_name(name: &str) -> Option<&'static Capability<Format>> {
+    FORMATS.iter().find(|c| c.name == name && codecs::can_encode(&c.value))
+}
+
+pub fn quality_by_name(name: &str) -> Option<&'static Capability<Quality>> {
+    QUALITIES.iter().find(|c| c.name == name)
+}
+
+/// Makes the configured custom qualities available to the parser and
+/// info.json. Names that shadow a standard quality are ignored.
+pub fn register_custom_qualities(names: impl IntoIterator<Item = String>) {
+    let names = names
+        .into_iter()
+        .filter(|name| {
+            let standard = quality_by_name(name).is_some();
+            if standard {
+                tracing::warn!("Ignoring custom quality '{}', which shadows a standard quality", name);
+            }
+            !standard
+        })
+        .collect();
+    if CUSTOM_QUALITIES.set(names).is_err() {
+        tracing::warn!("Custom qualities were already registered");
+    }
+}
+
+pub fn custom_quality(name: &str) -> Option<Quality> {
+    CUSTOM_QUALITIES
+        .get()?
+        .iter()
+        .find(|n| *n == name)
+        .map(|n| Quality::Custom(n.clone()))
+}
+
+fn required_at(required: &[(&str, u8)], name: &str, level: u8) -> bool {
+    required.iter().any(|(n, l)| *n == name && *l <= level)
+}
+
+fn satisfies(supported: &[&str], required: &[(&str, u8)], level: u8) -> bool {
+    required
+        .iter()
+        .filter(|(_, l)| *l <= level)
+        .all(|(name, _)| supported.contains(name))
+}
+
+fn names<T>(table: &[Capability<T>]) -> Vec<&'static str> {
+    table.iter().map(|c| c.name).collect()
+}
+
+fn format_names() -> Vec<&'static str> {
+    FORMATS.iter().filter(|c| codecs::can_encode(&c.value)).map(|c| c.name).collect()
+}
+
+/// Highest compliance level whose every requirement is supported
+pub fn compliance_level() -> u8 {
+    (0..=2)
+        .rev()
+        .find(|&level| {
+            satisfies(&format_names(), REQUIRED_FORMATS, level)
+                && satisfies(&names(QUALITIES), REQUIRED_QUALITIES, level)
+                && satisfies(FEATURES, REQUIRED_FEATURES, level)
+        })
+        .unwrap_or(0)
+}
+
+/// Supported formats, qualities and features beyond what `level` implies,
+/// as advertised in extraFormats/extraQualities/extraFeatures
+pub fn extra_formats(level: u8) -> Vec<String> {
+    extras(&format_names(), REQUIRED_FORMATS, level)
+}
+
+pub fn extra_qualities(level: u8) -> Vec<String> {
+    let mut qualities = extras(&names(QUALITIES), REQUIRED_QUALITIES, level);
+    qualities.extend(CUSTOM_QUALITIES.get().into_iter().flatten().cloned());
+    qualities
+}
+
+pub fn extra_features(level: u8) -> Vec<String> {
+    extras(FEATURES, REQUIRED_FEATURES, level)
+}
+
+fn extras(supported: &[&str], required: &[(&str, u8)], level: u8) -> Vec<String> {
+    supported
+        .iter()
+        .filter(|name| !required_at(required, name, level))
+        .map(|name| name.to_string())
+        .collect()
+}
@@ -0,0 +1,142 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Serialize;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum IiifError {
+    /// A URL segment does not match the Image API grammar
+    InvalidSyntax { segment: &'static str, value: String },
+    /// The requested region does not intersect the image
+    RegionOutOfBounds,
+    /// The region or size resolves to zero pixels
+    ZeroSize { segment: &'static str },
+    /// The requested size exceeds the region without the `^` prefix
+    UpscaleNotAllowed,
+    /// The output exceeds the configured maxWidth/maxHeight
+    SizeTooLarge { width: u32, height: u32 },
+    /// The output exceeds the configured maxArea
+    AreaTooLarge { area: u64, max_area: u64 },
+    /// The format is not one this server can produce
+    UnsupportedFormat { value: String },
+    /// The identifier could not be resolved to a source image
+    NotFound { identifier: String },
+    /// Missing or wrong admin credentials
+    Unauthorized,
+    /// The processing queue is full
+    Overloaded,
+    /// Rendering did not finish within the configured timeout
+    Timeout,
+    /// libvips or I/O failure while rendering
+    Processing(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: &'static str,
+    segment: Option<&'static str>,
+    message: &'a str,
+}
+
+impl IiifError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            IiifError::InvalidSyntax { .. }
+            | IiifError::RegionOutOfBounds
+            | IiifError::ZeroSize { .. }
+            | IiifError::UpscaleNotAllowed
+            | IiifError::SizeTooLarge { .. }
+            | IiifError::UnsupportedFormat { .. } => StatusCode::BAD_REQUEST,
+            IiifError::AreaTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            IiifError::NotFound { .. } => StatusCode::NOT_FOUND,
+            IiifError::Unauthorized => StatusCode::UNAUTHORIZED,
+            IiifError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            IiifError::Timeout => StatusCode::GATEWAY_TIMEOUT,
+            IiifError::Processing(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Stable machine-readable error code
+    pub fn code(&self) -> &'static str {
+        match self {
+            IiifError::InvalidSyntax { .. } => "invalid_syntax",
+            IiifError::RegionOutOfBounds => "region_out_of_bounds",
+            IiifError::ZeroSize { .. } => "zero_size",
+            IiifError::UpscaleNotAllowed => "upscale_not_allowed",
+            IiifError::SizeTooLarge { .. } => "size_too_large",
+            IiifError::AreaTooLarge { .. } => "area_too_large",
+            IiifError::UnsupportedFormat { .. } => "unsupported_format",
+            IiifError::NotFound { .. } => "not_found",
+            IiifError::Unauthorized => "unauthorized",
+            IiifError::Overloaded => "overloaded",
+            IiifError::Timeout => "timeout",
+            IiifError::Processing(_) => "processing_failed",
+        }
+    }
+
+    /// The URL segment the error refers to, if any
+    pub fn segment(&self) -> Option<&'static str> {
+        match self {
+            IiifError::InvalidSyntax { segment, .. } | IiifError::ZeroSize { segment } => Some(segment),
+            IiifError::RegionOutOfBounds => Some("region"),
+            IiifError::UpscaleNotAllowed
+            | IiifError::SizeTooLarge { .. }
+            | IiifError::AreaTooLarge { .. } => Some("size"),
+            IiifError::UnsupportedFormat { .. } => Some("format"),
+            IiifError::NotFound { .. } => Some("identifier"),
+            IiifError::Unauthorized | IiifError::Overloaded | IiifError::Timeout | IiifError::Processing(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for IiifError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IiifError::InvalidSyntax { segment, value } => write!(f, "Invalid {} '{}'", segment, value),
+            IiifError::RegionOutOfBounds => write!(f, "The requested region is outside the image"),
+            IiifError::ZeroSize { segment } => write!(f, "The requested {} has zero width or height", segment),
+            IiifError::UpscaleNotAllowed => write!(f, "Size is larger than the region; use the '^' prefix to upscale"),
+            IiifError::SizeTooLarge { width, height } => {
+                write!(f, "Requested size {}x{} exceeds the maximum allowed dimensions", width, height)
+            }
+            IiifError::AreaTooLarge { area, max_area } => {
+                write!(f, "Requested area {} exceeds the maximum of {} pixels", area, max_area)
+            }
+            IiifError::UnsupportedFormat { value } => write!(f, "Unsupported format '{}'", value),
+            IiifError::NotFound { identifier } => write!(f, "Image '{}' not found", identifier),
+            IiifError::Unauthorized => write!(f, "Missing or invalid admin token"),
+            IiifError::Overloaded => write!(f, "Server is busy, try again later"),
+            IiifError::Timeout => write!(f, "Image processing timed out"),
+            IiifError::Processing(msg) => write!(f, "Image processing failed: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for IiifError {}
+
+impl From<libvips_rs::error::Error> for IiifError {
+    fn from(e: libvips_rs::error::Error) -> Self {
+        IiifError::Processing(format!("{:?}", e))
+    }
+}
+
+impl IntoResponse for IiifError {
+    fn into_response(self) -> Response {
+        // Internal failures are logged in full but not echoed to clients
+        let message = if let IiifError::Processing(msg) = &self {
+            tracing::error!("Image processing error: {}", msg);
+            "Image processing failed".to_string()
+        } else {
+            self.to_string()
+        };
+        let body = ErrorBody {
+            error: self.code(),
+            segment: self.segment(),
+            message: &message,
+        };
+        (self.status(), Json(body)).into_response()
+    }
+}
@@ -1,4 +1,7 @@
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use crate::iiif::capabilities;
+use crate::iiif::types::SizeLimits;
 
 #[derive(Serialize)]
 #[serde(rename_all = "camelCase")]
@@ -11,24 +14,131 @@ pub struct ImageInfo {
     pub profile: String,
     pub width: u32,
     pub height: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_width: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_height: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_area: Option<u64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tiles: Vec<TileInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sizes: Vec<SizeInfo>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preferred_formats: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_formats: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extra_qualities: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub extra_features: Vec<String>,
+    #[serde(flatten)]
+    pub linking: LinkingProperties,
+}
+
+/// Rights and linking properties configured per image or per directory
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LinkingProperties {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rights: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub part_of: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub see_also: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service: Vec<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub logo: Vec<Value>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TileInfo {
+    pub width: u32,
+    pub height: u32,
+    pub scale_factors: Vec<u32>,
+}
+
+#[derive(Serialize)]
+pub struct SizeInfo {
+    pub width: u32,
+    pub height: u32,
 }
 
 impl ImageInfo {
     pub fn new(id: String, width: u32, height: u32) -> Self {
+        let level = capabilities::compliance_level();
         Self {
             context: "http://iiif.io/api/image/3/context.json".to_string(),
             id,
             r#type: "ImageService3".to_string(),
             protocol: "http://iiif.io/api/image".to_string(),
-            profile: "level2".to_string(),
+            profile: format!("level{}", level),
             width,
             height,
-            extra_features: vec![
-                "rotationArbitrary".to_string(),
-                "mirroring".to_string(),
-                "regionSquare".to_string(),
-            ],
+            max_width: None,
+            max_height: None,
+            max_area: None,
+            tiles: Vec::new(),
+            sizes: Vec::new(),
+            preferred_formats: Vec::new(),
+            extra_formats: capabilities::extra_formats(level),
+            extra_qualities: capabilities::extra_qualities(level),
+            extra_features: capabilities::extra_features(level),
+            linking: LinkingProperties::default(),
+        }
+    }
+
+    /// Advertises square tiles of `tile_size` with power-of-two scale factors
+    /// down to a single tile, and the downsampled sizes that are cheap to
+    /// serve: the pyramid `levels` when the source has them, otherwise the
+    /// scale-factor reductions.
+    pub fn with_tiles(mut self, tile_size: u32, levels: &[(u32, u32)]) -> Self {
+        let tile_size = tile_size.max(1);
+        let longest = self.width.max(self.height);
+        let mut scale_factors = vec![1];
+        while longest.div_ceil(*scale_factors.last().unwrap()) > tile_size {
+            scale_factors.push(scale_factors.last().unwrap() * 2);
         }
+
+        let mut sizes: Vec<SizeInfo> = if levels.is_empty() {
+            scale_factors[1..]
+                .iter()
+                .map(|&f| SizeInfo { width: self.width.div_ceil(f), height: self.height.div_ceil(f) })
+                .collect()
+        } else {
+            levels.iter().map(|&(width, height)| SizeInfo { width, height }).collect()
+        };
+        sizes.sort_by_key(|size| size.width);
+
+        self.tiles = vec![TileInfo { width: tile_size, height: tile_size, scale_factors }];
+        self.sizes = sizes;
+        self
+    }
+
+    /// Advertises the configured maxWidth/maxHeight/maxArea and drops any
+    /// listed sizes that exceed them. Call after `with_tiles`.
+    pub fn with_limits(mut self, limits: &SizeLimits) -> Self {
+        self.max_width = limits.max_width;
+        self.max_height = limits.max_height;
+        self.max_area = limits.max_area;
+        self.sizes.retain(|size| limits.check(size.width, size.height).is_ok());
+        self
+    }
+
+    /// Lists the configured preferred formats that this server can produce
+    pub fn with_preferred_formats(mut self, formats: &[String]) -> Self {
+        self.preferred_formats = formats
+            .iter()
+            .filter(|f| capabilities::format_by_name(f).is_some())
+            .cloned()
+            .collect();
+        self
+    }
+
+    pub fn with_linking(mut self, linking: LinkingProperties) -> Self {
+        self.linking = linking;
+        self
     }
 }
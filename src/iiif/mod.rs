@@ -1,3 +1,5 @@
 pub mod types;
 pub mod parser;
 pub mod info;
+pub mod error;
+pub mod capabilities;
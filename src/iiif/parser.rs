@@ -1,91 +1,190 @@
+use crate::iiif::capabilities;
+use crate::iiif::error::IiifError;
 use crate::iiif::types::*;
 
-pub fn parse_region(s: &str) -> Option<Region> {
+pub fn parse_region(s: &str) -> Result<Region, IiifError> {
+    let invalid = || IiifError::InvalidSyntax { segment: "region", value: s.to_string() };
+
     if s == "full" {
-        return Some(Region::Full);
+        return Ok(Region::Full);
     }
     if s == "square" {
-        return Some(Region::Square);
+        return Ok(Region::Square);
     }
 
     if let Some(rest) = s.strip_prefix("pct:") {
-        let parts: Vec<&str> = rest.split(',').collect();
-        if parts.len() == 4 {
-            let x = parts[0].parse().ok()?;
-            let y = parts[1].parse().ok()?;
-            let w = parts[2].parse().ok()?;
-            let h = parts[3].parse().ok()?;
-            return Some(Region::Percentage(x, y, w, h));
-        }
-    } else {
-        let parts: Vec<&str> = s.split(',').collect();
-        if parts.len() == 4 {
-            let x = parts[0].parse().ok()?;
-            let y = parts[1].parse().ok()?;
-            let w = parts[2].parse().ok()?;
-            let h = parts[3].parse().ok()?;
-            return Some(Region::Absolute(x, y, w, h));
+        let [x, y, w, h] = parse_region_values(rest, |part| {
+            part.parse::<f64>().ok().filter(|v| v.is_finite() && *v >= 0.0)
+        })
+        .ok_or_else(invalid)?;
+        if w == 0.0 || h == 0.0 {
+            return Err(IiifError::ZeroSize { segment: "region" });
         }
+        return Ok(Region::Percentage(x, y, w, h));
+    }
+
+    // regionByPx takes whole pixels only
+    let [x, y, w, h] = parse_region_values(s, |part| part.parse::<u32>().ok()).ok_or_else(invalid)?;
+    if w == 0 || h == 0 {
+        return Err(IiifError::ZeroSize { segment: "region" });
     }
-    None
+    Ok(Region::Absolute(x, y, w, h))
 }
 
-pub fn parse_size(s: &str) -> Option<Size> {
+// Splits "x,y,w,h" and parses each of the four parts with `parse`
+fn parse_region_values<T: Copy + Default>(s: &str, parse: impl Fn(&str) -> Option<T>) -> Option<[T; 4]> {
+    let parts: Vec<&str> = s.split(',').collect();
+    if parts.len() != 4 {
+        return None;
+    }
+    let mut values = [T::default(); 4];
+    for (value, part) in values.iter_mut().zip(&parts) {
+        *value = parse(part)?;
+    }
+    Some(values)
+}
+
+pub fn parse_size(s: &str) -> Result<Size, IiifError> {
+    let parsed = match s.strip_prefix('^') {
+        Some(rest) => parse_upscale_size(rest),
+        None => parse_plain_size(s),
+    };
+    parsed.ok_or_else(|| IiifError::InvalidSyntax { segment: "size", value: s.to_string() })
+}
+
+fn parse_plain_size(s: &str) -> Option<Size> {
     if s == "max" {
         return Some(Size::Max);
     }
-    if s == "^max" {
-        return Some(Size::ScaleAsFull);
-    }
     if let Some(rest) = s.strip_prefix("pct:") {
-        let n: f64 = rest.parse().ok()?;
-        return Some(Size::Percentage(n));
-    }
-    if s.contains(',') {
-        let parts: Vec<&str> = s.split(',').collect();
-        if parts.len() == 2 {
-            match (parts[0], parts[1]) {
-                ("", h) => return Some(Size::Height(h.parse().ok()?)),
-                (w, "") => return Some(Size::Width(w.parse().ok()?)),
-                (w, h) => {
-                    let w_val = w.parse().ok()?;
-                    let h_val = h.parse().ok()?;
-                    return Some(Size::WidthHeight(w_val, h_val));
-                }
-            }
-        }
+        return Some(Size::Percentage(parse_percentage(rest)?));
+    }
+    if let Some(rest) = s.strip_prefix('!') {
+        let (w, h) = parse_width_height(rest)?;
+        return Some(Size::WidthHeightMin(w?, h?));
+    }
+    match parse_width_height(s)? {
+        (Some(w), None) => Some(Size::Width(w)),
+        (None, Some(h)) => Some(Size::Height(h)),
+        (Some(w), Some(h)) => Some(Size::WidthHeight(w, h)),
+        (None, None) => None,
     }
-    // TODO: Handle !w,h
-    None
 }
 
-pub fn parse_rotation(s: &str) -> Option<Rotation> {
+fn parse_upscale_size(s: &str) -> Option<Size> {
+    if s == "max" {
+        return Some(Size::ScaleAsFull);
+    }
+    if let Some(rest) = s.strip_prefix("pct:") {
+        return Some(Size::UpscalePercentage(parse_percentage(rest)?));
+    }
     if let Some(rest) = s.strip_prefix('!') {
-        let degrees = rest.parse().ok()?;
-        return Some(Rotation { degrees, mirror: true });
+        let (w, h) = parse_width_height(rest)?;
+        return Some(Size::UpscaleWidthHeightMin(w?, h?));
+    }
+    match parse_width_height(s)? {
+        (Some(w), None) => Some(Size::UpscaleWidth(w)),
+        (None, Some(h)) => Some(Size::UpscaleHeight(h)),
+        (Some(w), Some(h)) => Some(Size::UpscaleWidthHeight(w, h)),
+        (None, None) => None,
     }
-    let degrees = s.parse().ok()?;
-    Some(Rotation { degrees, mirror: false })
 }
 
-pub fn parse_quality(s: &str) -> Option<Quality> {
-    match s {
-        "default" => Some(Quality::Default),
-        "color" => Some(Quality::Color),
-        "gray" => Some(Quality::Gray),
-        "bitonal" => Some(Quality::Bitonal),
-        _ => None,
-    }
+fn parse_percentage(s: &str) -> Option<f64> {
+    let n: f64 = s.parse().ok()?;
+    (n.is_finite() && n >= 0.0).then_some(n)
+}
+
+// Splits "w,h", "w," or ",h" into optional components
+fn parse_width_height(s: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let (w, h) = s.split_once(',')?;
+    let w = if w.is_empty() { None } else { Some(w.parse().ok()?) };
+    let h = if h.is_empty() { None } else { Some(h.parse().ok()?) };
+    Some((w, h))
 }
 
-pub fn parse_format(s: &str) -> Option<Format> {
-    match s {
-        "jpg" => Some(Format::Jpg),
-        "png" => Some(Format::Png),
-        "tif" => Some(Format::Tif),
-        "webp" => Some(Format::Webp),
-        "gif" => Some(Format::Gif),
-        "pdf" => Some(Format::Pdf),
-        _ => None,
+pub fn parse_rotation(s: &str) -> Result<Rotation, IiifError> {
+    let (rest, mirror) = match s.strip_prefix('!') {
+        Some(rest) => (rest, true),
+        None => (s, false),
+    };
+    let degrees: f64 = rest
+        .parse()
+        .ok()
+        .filter(|d: &f64| (0.0..=360.0).contains(d))
+        .ok_or_else(|| IiifError::InvalidSyntax { segment: "rotation", value: s.to_string() })?;
+    Ok(Rotation { degrees, mirror })
+}
+
+pub fn parse_quality(s: &str) -> Result<Quality, IiifError> {
+    capabilities::quality_by_name(s)
+        .map(|c| c.value.clone())
+        .or_else(|| capabilities::custom_quality(s))
+        .ok_or_else(|| IiifError::InvalidSyntax { segment: "quality", value: s.to_string() })
+}
+
+pub fn parse_format(s: &str) -> Result<Format, IiifError> {
+    capabilities::format_by_name(s)
+        .map(|c| c.value.clone())
+        .ok_or_else(|| IiifError::UnsupportedFormat { value: s.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_regions() {
+        assert_eq!(parse_region("full").unwrap(), Region::Full);
+        assert_eq!(parse_region("square").unwrap(), Region::Square);
+        assert_eq!(parse_region("10,20,30,40").unwrap(), Region::Absolute(10, 20, 30, 40));
+        assert_eq!(parse_region("pct:10,20.5,30,40").unwrap(), Region::Percentage(10.0, 20.5, 30.0, 40.0));
+    }
+
+    #[test]
+    fn rejects_fractional_pixel_regions() {
+        for input in ["10.5,20,30,40", "0,0,1e3,10", "-1,0,10,10", "0,0,10", "0,0,10,10,10", "pct:0,0,-1,10"] {
+            assert!(
+                matches!(parse_region(input), Err(IiifError::InvalidSyntax { segment: "region", .. })),
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_zero_sized_regions() {
+        assert_eq!(parse_region("0,0,0,10"), Err(IiifError::ZeroSize { segment: "region" }));
+        assert_eq!(parse_region("pct:0,0,10,0"), Err(IiifError::ZeroSize { segment: "region" }));
+    }
+
+    #[test]
+    fn parses_every_size_form() {
+        let cases = [
+            ("max", Size::Max),
+            ("^max", Size::ScaleAsFull),
+            ("150,", Size::Width(150)),
+            ("^150,", Size::UpscaleWidth(150)),
+            (",150", Size::Height(150)),
+            ("^,150", Size::UpscaleHeight(150)),
+            ("150,75", Size::WidthHeight(150, 75)),
+            ("^150,75", Size::UpscaleWidthHeight(150, 75)),
+            ("pct:50", Size::Percentage(50.0)),
+            ("^pct:150", Size::UpscalePercentage(150.0)),
+            ("!150,75", Size::WidthHeightMin(150, 75)),
+            ("^!150,75", Size::UpscaleWidthHeightMin(150, 75)),
+        ];
+        for (input, expected) in cases {
+            assert_eq!(parse_size(input).unwrap(), expected, "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_sizes() {
+        for input in ["", ",", "^", "full", "^^max", "!150,", "pct:-1", "pct:abc", "150", "-1,", "1.5,"] {
+            assert!(
+                matches!(parse_size(input), Err(IiifError::InvalidSyntax { segment: "size", .. })),
+                "{input}"
+            );
+        }
     }
 }
@@ -1,4 +1,5 @@
 use serde::{Deserialize, Serialize};
+use crate::iiif::error::IiifError;
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ImageRequest {
@@ -15,20 +16,185 @@ pub enum Region {
     Full,
     Square,
     // x,y,w,h
-    Absolute(f64, f64, f64, f64),
+    Absolute(u32, u32, u32, u32),
     // pct:x,y,w,h
     Percentage(f64, f64, f64, f64),
 }
 
+impl Region {
+    /// Resolves the region against an image of `width` x `height` pixels.
+    /// Regions extending past the edge are cropped to the image bounds;
+    /// regions entirely outside the image are rejected.
+    pub fn resolve(&self, width: u32, height: u32) -> Result<Rect, IiifError> {
+        let (iw, ih) = (width as f64, height as f64);
+        let (x, y, w, h) = match *self {
+            Region::Full => return Ok(Rect { x: 0, y: 0, width, height }),
+            Region::Square => {
+                let side = width.min(height);
+                return Ok(Rect { x: (width - side) / 2, y: (height - side) / 2, width: side, height: side });
+            }
+            Region::Absolute(x, y, w, h) => (x as f64, y as f64, w as f64, h as f64),
+            Region::Percentage(px, py, pw, ph) => {
+                (iw * px / 100.0, ih * py / 100.0, iw * pw / 100.0, ih * ph / 100.0)
+            }
+        };
+
+        let (x0, y0) = (x.round(), y.round());
+        if x0 >= iw || y0 >= ih {
+            return Err(IiifError::RegionOutOfBounds);
+        }
+        let x1 = (x + w).round().min(iw);
+        let y1 = (y + h).round().min(ih);
+        if x1 <= x0 || y1 <= y0 {
+            return Err(IiifError::ZeroSize { segment: "region" });
+        }
+        Ok(Rect {
+            x: x0 as u32,
+            y: y0 as u32,
+            width: (x1 - x0) as u32,
+            height: (y1 - y0) as u32,
+        })
+    }
+}
+
+/// A pixel rectangle within the source image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Rect {
+    pub fn is_full(&self, width: u32, height: u32) -> bool {
+        self.x == 0 && self.y == 0 && self.width == width && self.height == height
+    }
+
+    /// Maps the rectangle onto a copy of the image scaled by `sx`/`sy`
+    /// (e.g. a pyramid level), clamped to that copy's `width` x `height`.
+    pub fn scale(&self, sx: f64, sy: f64, width: u32, height: u32) -> Rect {
+        let x0 = ((self.x as f64 * sx).floor() as u32).min(width.saturating_sub(1));
+        let y0 = ((self.y as f64 * sy).floor() as u32).min(height.saturating_sub(1));
+        let x1 = (((self.x + self.width) as f64 * sx).ceil() as u32).clamp(x0 + 1, width);
+        let y1 = (((self.y + self.height) as f64 * sy).ceil() as u32).clamp(y0 + 1, height);
+        Rect { x: x0, y: y0, width: x1 - x0, height: y1 - y0 }
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub enum Size {
     Max,
     ScaleAsFull, // ^max
-    Width(u32),
-    Height(u32),
-    WidthHeight(u32, u32),
-    Percentage(f64),
+    Width(u32), // w,
+    Height(u32), // ,h
+    WidthHeight(u32, u32), // w,h
+    Percentage(f64), // pct:n
     WidthHeightMin(u32, u32), // !w,h
+    UpscaleWidth(u32), // ^w,
+    UpscaleHeight(u32), // ^,h
+    UpscaleWidthHeight(u32, u32), // ^w,h
+    UpscalePercentage(f64), // ^pct:n
+    UpscaleWidthHeightMin(u32, u32), // ^!w,h
+}
+
+impl Size {
+    /// Whether the request carried the `^` prefix and may exceed the region size.
+    pub fn allows_upscaling(&self) -> bool {
+        matches!(
+            self,
+            Size::ScaleAsFull
+                | Size::UpscaleWidth(_)
+                | Size::UpscaleHeight(_)
+                | Size::UpscaleWidthHeight(_, _)
+                | Size::UpscalePercentage(_)
+                | Size::UpscaleWidthHeightMin(_, _)
+        )
+    }
+
+    /// Computes the output dimensions for a region of `width` x `height` pixels,
+    /// honouring the upscaling rules and the server's size limits.
+    pub fn resolve(&self, width: u32, height: u32, limits: &SizeLimits) -> Result<(u32, u32), IiifError> {
+        let (rw, rh) = (width as f64, height as f64);
+        let (w, h) = match *self {
+            // Rounded down (with float slack) so the result never overshoots the limits
+            Size::Max => {
+                let scale = limits.fit_scale(rw, rh).min(1.0);
+                ((rw * scale + 1e-9).floor(), (rh * scale + 1e-9).floor())
+            }
+            Size::ScaleAsFull => {
+                let scale = limits.fit_scale(rw, rh);
+                ((rw * scale + 1e-9).floor(), (rh * scale + 1e-9).floor())
+            }
+            Size::Width(w) | Size::UpscaleWidth(w) => (w as f64, (rh * w as f64 / rw).round()),
+            Size::Height(h) | Size::UpscaleHeight(h) => ((rw * h as f64 / rh).round(), h as f64),
+            Size::WidthHeight(w, h) | Size::UpscaleWidthHeight(w, h) => (w as f64, h as f64),
+            Size::Percentage(n) | Size::UpscalePercentage(n) => {
+                ((rw * n / 100.0).round(), (rh * n / 100.0).round())
+            }
+            Size::WidthHeightMin(w, h) => {
+                let scale = (w as f64 / rw).min(h as f64 / rh).min(1.0);
+                ((rw * scale).round(), (rh * scale).round())
+            }
+            Size::UpscaleWidthHeightMin(w, h) => {
+                let scale = (w as f64 / rw).min(h as f64 / rh);
+                ((rw * scale).round(), (rh * scale).round())
+            }
+        };
+
+        if !w.is_finite() || !h.is_finite() || w < 1.0 || h < 1.0 {
+            return Err(IiifError::ZeroSize { segment: "size" });
+        }
+        if !self.allows_upscaling() && (w > rw || h > rh) {
+            return Err(IiifError::UpscaleNotAllowed);
+        }
+        let (w, h) = (w as u32, h as u32);
+        limits.check(w, h)?;
+        Ok((w, h))
+    }
+}
+
+/// Output size limits advertised as maxWidth/maxHeight/maxArea
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SizeLimits {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    pub max_area: Option<u64>,
+}
+
+impl SizeLimits {
+    // Per the spec, maxHeight defaults to maxWidth when only the latter is given
+    fn effective_max_height(&self) -> Option<u32> {
+        self.max_height.or(self.max_width)
+    }
+
+    /// Largest uniform scale factor that keeps `w` x `h` within the limits.
+    fn fit_scale(&self, w: f64, h: f64) -> f64 {
+        let mut scale = f64::INFINITY;
+        if let Some(mw) = self.max_width {
+            scale = scale.min(mw as f64 / w);
+        }
+        if let Some(mh) = self.effective_max_height() {
+            scale = scale.min(mh as f64 / h);
+        }
+        if let Some(ma) = self.max_area {
+            scale = scale.min((ma as f64 / (w * h)).sqrt());
+        }
+        if scale.is_finite() { scale } else { 1.0 }
+    }
+
+    pub fn check(&self, width: u32, height: u32) -> Result<(), IiifError> {
+        if self.max_width.is_some_and(|mw| width > mw)
+            || self.effective_max_height().is_some_and(|mh| height > mh)
+        {
+            return Err(IiifError::SizeTooLarge { width, height });
+        }
+        let area = width as u64 * height as u64;
+        if let Some(max_area) = self.max_area.filter(|&ma| area > ma) {
+            return Err(IiifError::AreaTooLarge { area, max_area });
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -43,6 +209,8 @@ pub enum Quality {
     Color,
     Gray,
     Bitonal,
+    /// A quality defined under `[qualities]` in the config
+    Custom(String),
 }
 
 #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -53,4 +221,160 @@ pub enum Format {
     Webp,
     Gif,
     Pdf,
+    Avif,
+    Jp2,
+    Jxl,
+}
+
+impl Format {
+    /// Media type sent as the response Content-Type
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            Format::Jpg => "image/jpeg",
+            Format::Png => "image/png",
+            Format::Tif => "image/tiff",
+            Format::Webp => "image/webp",
+            Format::Gif => "image/gif",
+            Format::Pdf => "application/pdf",
+            Format::Avif => "image/avif",
+            Format::Jp2 => "image/jp2",
+            Format::Jxl => "image/jxl",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_LIMITS: SizeLimits = SizeLimits { max_width: None, max_height: None, max_area: None };
+
+    #[test]
+    fn resolves_regions_inside_the_image() {
+        assert_eq!(Region::Full.resolve(400, 300).unwrap(), Rect { x: 0, y: 0, width: 400, height: 300 });
+        assert_eq!(Region::Square.resolve(400, 300).unwrap(), Rect { x: 50, y: 0, width: 300, height: 300 });
+        assert_eq!(
+            Region::Absolute(10, 20, 100, 50).resolve(400, 300).unwrap(),
+            Rect { x: 10, y: 20, width: 100, height: 50 }
+        );
+        assert_eq!(
+            Region::Percentage(25.0, 50.0, 50.0, 50.0).resolve(400, 300).unwrap(),
+            Rect { x: 100, y: 150, width: 200, height: 150 }
+        );
+    }
+
+    #[test]
+    fn crops_regions_partly_out_of_bounds() {
+        assert_eq!(
+            Region::Absolute(300, 200, 500, 500).resolve(400, 300).unwrap(),
+            Rect { x: 300, y: 200, width: 100, height: 100 }
+        );
+        assert_eq!(
+            Region::Percentage(50.0, 50.0, 100.0, 100.0).resolve(400, 300).unwrap(),
+            Rect { x: 200, y: 150, width: 200, height: 150 }
+        );
+    }
+
+    #[test]
+    fn rejects_regions_fully_out_of_bounds() {
+        assert_eq!(Region::Absolute(400, 0, 10, 10).resolve(400, 300), Err(IiifError::RegionOutOfBounds));
+        assert_eq!(Region::Absolute(0, 300, 10, 10).resolve(400, 300), Err(IiifError::RegionOutOfBounds));
+        assert_eq!(Region::Absolute(1000, 1000, 10, 10).resolve(400, 300), Err(IiifError::RegionOutOfBounds));
+        assert_eq!(Region::Percentage(100.0, 0.0, 10.0, 10.0).resolve(400, 300), Err(IiifError::RegionOutOfBounds));
+    }
+
+    #[test]
+    fn resolves_sizes_within_the_region() {
+        let cases = [
+            (Size::Max, (400, 300)),
+            (Size::Width(200), (200, 150)),
+            (Size::Height(150), (200, 150)),
+            (Size::WidthHeight(100, 100), (100, 100)),
+            (Size::Percentage(50.0), (200, 150)),
+            (Size::WidthHeightMin(200, 200), (200, 150)),
+            (Size::WidthHeightMin(800, 800), (400, 300)),
+        ];
+        for (size, expected) in cases {
+            assert_eq!(size.resolve(400, 300, &NO_LIMITS).unwrap(), expected, "{size:?}");
+        }
+    }
+
+    #[test]
+    fn resolves_upscaled_sizes() {
+        let cases = [
+            (Size::ScaleAsFull, (400, 300)),
+            (Size::UpscaleWidth(800), (800, 600)),
+            (Size::UpscaleHeight(600), (800, 600)),
+            (Size::UpscaleWidthHeight(500, 500), (500, 500)),
+            (Size::UpscalePercentage(150.0), (600, 450)),
+            (Size::UpscaleWidthHeightMin(800, 800), (800, 600)),
+        ];
+        for (size, expected) in cases {
+            assert_eq!(size.resolve(400, 300, &NO_LIMITS).unwrap(), expected, "{size:?}");
+        }
+    }
+
+    #[test]
+    fn upscaled_forms_also_allow_downscaling() {
+        assert_eq!(Size::UpscaleWidth(200).resolve(400, 300, &NO_LIMITS).unwrap(), (200, 150));
+        assert_eq!(Size::UpscalePercentage(50.0).resolve(400, 300, &NO_LIMITS).unwrap(), (200, 150));
+    }
+
+    #[test]
+    fn rejects_upscaling_without_caret() {
+        for size in [
+            Size::Width(401),
+            Size::Height(301),
+            Size::WidthHeight(401, 300),
+            Size::WidthHeight(400, 301),
+            Size::Percentage(101.0),
+        ] {
+            assert_eq!(size.resolve(400, 300, &NO_LIMITS), Err(IiifError::UpscaleNotAllowed), "{size:?}");
+        }
+    }
+
+    #[test]
+    fn rejects_sizes_that_round_to_zero() {
+        assert_eq!(Size::Percentage(0.0).resolve(400, 300, &NO_LIMITS), Err(IiifError::ZeroSize { segment: "size" }));
+        assert_eq!(Size::Width(1).resolve(4000, 10, &NO_LIMITS), Err(IiifError::ZeroSize { segment: "size" }));
+    }
+
+    #[test]
+    fn scale_as_full_fits_the_limits() {
+        let limits = SizeLimits { max_width: Some(1000), max_height: None, max_area: None };
+        assert_eq!(Size::ScaleAsFull.resolve(400, 300, &limits).unwrap(), (1000, 750));
+    }
+
+    #[test]
+    fn max_height_falls_back_to_max_width() {
+        let limits = SizeLimits { max_width: Some(500), max_height: None, max_area: None };
+        assert_eq!(Size::Max.resolve(1000, 2000, &limits).unwrap(), (250, 500));
+        assert_eq!(
+            Size::WidthHeight(400, 600).resolve(1000, 2000, &limits),
+            Err(IiifError::SizeTooLarge { width: 400, height: 600 })
+        );
+    }
+
+    #[test]
+    fn explicit_max_height_overrides_max_width() {
+        let limits = SizeLimits { max_width: Some(500), max_height: Some(1000), max_area: None };
+        assert_eq!(Size::Max.resolve(1000, 2000, &limits).unwrap(), (500, 1000));
+        assert!(Size::WidthHeight(400, 600).resolve(1000, 2000, &limits).is_ok());
+    }
+
+    #[test]
+    fn max_area_is_rejected_with_413() {
+        let limits = SizeLimits { max_width: None, max_height: None, max_area: Some(10_000) };
+        let err = Size::WidthHeight(200, 100).resolve(400, 300, &limits).unwrap_err();
+        assert_eq!(err, IiifError::AreaTooLarge { area: 20_000, max_area: 10_000 });
+        assert_eq!(err.status(), axum::http::StatusCode::PAYLOAD_TOO_LARGE);
+    }
+
+    #[test]
+    fn max_fits_within_max_area() {
+        let limits = SizeLimits { max_width: None, max_height: None, max_area: Some(10_000) };
+        let (w, h) = Size::Max.resolve(400, 300, &limits).unwrap();
+        assert!(w as u64 * h as u64 <= 10_000);
+        assert_eq!((w, h), (115, 86));
+    }
 }
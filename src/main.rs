@@ -1,33 +1,43 @@
+mod admin;
 mod config;
 mod iiif;
+mod metadata;
 mod processor;
 mod cache;
 mod resolver;
 
 use crate::config::Config;
+use crate::iiif::error::IiifError;
+use crate::iiif::capabilities;
 use crate::iiif::parser;
 use crate::iiif::types::*;
 use crate::iiif::info::ImageInfo;
-use crate::processor::ImageProcessor;
+use crate::processor::{pyramid, ImageProcessor};
+use crate::processor::pool::WorkerPool;
 use crate::cache::TileCache;
+use crate::cache::info::{InfoCache, SourceInfo};
 use crate::resolver::Resolver;
 
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
-    response::IntoResponse,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware,
+    response::{IntoResponse, Redirect, Response},
     routing::get,
     Json,
     Router,
 };
 use std::net::SocketAddr;
 use std::sync::Arc;
+use std::time::Duration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
 struct AppState {
     config: Config,
-    processor: ImageProcessor,
+    processor: Arc<ImageProcessor>,
+    pool: WorkerPool,
     cache: TileCache,
+    info: InfoCache,
     resolver: Resolver,
 }
 
@@ -41,6 +51,7 @@ async fn main() {
         .init();
 
     let cfg = Config::load().expect("Failed to load configuration");
+    capabilities::register_custom_qualities(cfg.qualities.keys().cloned());
     
     std::fs::create_dir_all(&cfg.cache.disk_cache_dir).expect("Failed to create cache directory");
     if let Some(remote) = &cfg.remote {
@@ -49,15 +60,31 @@ async fn main() {
 
     let state = Arc::new(AppState {
         config: cfg.clone(),
-        processor: ImageProcessor::new(),
-        cache: TileCache::new(cfg.cache.disk_cache_dir.clone(), cfg.parse_memory_limit()),
+        processor: Arc::new(ImageProcessor::new(cfg.clone())),
+        pool: WorkerPool::new(
+            cfg.worker_count(),
+            cfg.processing.queue_limit,
+            Duration::from_secs(cfg.processing.timeout_secs),
+        ),
+        cache: TileCache::new(
+            cfg.cache.disk_cache_dir.clone(),
+            cfg.parse_memory_limit(),
+            cfg.parse_disk_limit(),
+        ),
+        info: InfoCache::new(Duration::from_secs(cfg.cache.info_ttl_secs)),
         resolver: Resolver::new(cfg.clone()),
     });
 
-    let app = Router::new()
+    let mut app = Router::new()
         .route("/", get(|| async { "Rust IIIF Server is running" }))
+        .route("/stats", get(handle_stats))
         // Using {*path} to capture identifiers with slashes
-        .route("/iiif/3/{*full_path}", get(handle_iiif))
+        .route("/iiif/3/{*full_path}", get(handle_iiif));
+    if cfg.admin.is_some() {
+        app = app.merge(admin::router());
+    }
+    let app = app
+        .layer(middleware::map_response(add_cors_header))
         .with_state(state);
 
     let victory_msg = format!("Listening on http://{}:{}", cfg.server.host, cfg.server.port);
@@ -70,8 +97,17 @@ async fn main() {
     axum::serve(listener, app).await.unwrap();
 }
 
+// Image API responses may be fetched cross-origin by any viewer
+async fn add_cors_header(mut response: Response) -> Response {
+    response
+        .headers_mut()
+        .insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    response
+}
+
 async fn handle_iiif(
     State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
     Path(full_path): Path<String>,
 ) -> impl IntoResponse {
     let segments: Vec<&str> = full_path.split('/').collect();
@@ -79,7 +115,11 @@ async fn handle_iiif(
     // 1. Check for info.json
     if segments.last() == Some(&"info.json") {
         let identifier = segments[..segments.len() - 1].join("/");
-        return get_info_logic(state, identifier).await.into_response();
+        let wants_jsonld = headers
+            .get(header::ACCEPT)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.contains("application/ld+json"));
+        return get_info_logic(state, identifier, wants_jsonld).await.into_response();
     }
 
     // 2. Check for image request (identifier / region / size / rotation / quality_format)
@@ -94,30 +134,48 @@ async fn handle_iiif(
         return get_image_logic(state, identifier, region_str, size_str, rotation_str, quality_format).await.into_response();
     }
 
-    (StatusCode::BAD_REQUEST, "Invalid IIIF request").into_response()
+    // 3. A bare identifier is the base URI; send clients to its info.json
+    if !full_path.is_empty() {
+        let info_url = format!("{}{}/info.json", state.config.iiif.base_url, full_path);
+        return Redirect::to(&info_url).into_response();
+    }
+
+    IiifError::InvalidSyntax { segment: "path", value: full_path }.into_response()
+}
+
+async fn handle_stats(State(state): State<Arc<AppState>>) -> impl IntoResponse {
+    Json(state.cache.stats())
 }
 
 async fn get_info_logic(
     state: Arc<AppState>,
     identifier: String,
-) -> impl IntoResponse {
-    match state.resolver.resolve(&identifier).await {
-        Some(path) => {
-            let path_str = path.to_string_lossy();
-            match state.processor.get_image_size(&path_str, &identifier) {
-                Ok((w, h)) => {
-                    let id_url = format!("{}{}", state.config.iiif.base_url, identifier);
-                    let info = ImageInfo::new(id_url, w as u32, h as u32);
-                    (StatusCode::OK, Json(info)).into_response()
-                }
-                Err(e) => {
-                    tracing::error!("Failed to get image size: {:?}", e);
-                    (StatusCode::INTERNAL_SERVER_ERROR, "Failed to get image info").into_response()
-                }
-            }
-        }
-        None => (StatusCode::NOT_FOUND, "Image not found").into_response(),
-    }
+    wants_jsonld: bool,
+) -> Result<Response, IiifError> {
+    let path = state
+        .resolver
+        .resolve(&identifier)
+        .await
+        .ok_or_else(|| IiifError::NotFound { identifier: identifier.clone() })?;
+    let version = state.resolver.source_version(&path).await.unwrap_or_default();
+    let source = source_info(&state, &identifier, &path, version).await?;
+
+    let meta = &source.metadata;
+    let levels: Vec<(u32, u32)> = meta.levels.iter().map(|level| (level.width, level.height)).collect();
+    let id_url = format!("{}{}", state.config.iiif.base_url, identifier);
+    let info = ImageInfo::new(id_url, meta.width, meta.height)
+        .with_tiles(state.config.iiif.tile_size, &levels)
+        .with_limits(&state.config.size_limits())
+        .with_preferred_formats(&state.config.iiif.preferred_formats)
+        .with_linking(source.linking.clone());
+
+    // JSON-LD only when explicitly asked for, as the spec requires
+    let content_type = if wants_jsonld {
+        "application/ld+json;profile=\"http://iiif.io/api/image/3/context.json\""
+    } else {
+        "application/json"
+    };
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, content_type)], Json(info)).into_response())
 }
 
 async fn get_image_logic(
@@ -127,55 +185,92 @@ async fn get_image_logic(
     size_str: String,
     rotation_str: String,
     quality_format: String,
-) -> impl IntoResponse {
-    let parts: Vec<&str> = quality_format.split('.').collect();
-    if parts.len() != 2 {
-        return (StatusCode::BAD_REQUEST, "Invalid quality.format").into_response();
+) -> Result<Response, IiifError> {
+    let (quality_str, format_str) = quality_format
+        .split_once('.')
+        .ok_or_else(|| IiifError::InvalidSyntax { segment: "quality", value: quality_format.clone() })?;
+
+    let req = ImageRequest {
+        identifier: identifier.clone(),
+        region: parser::parse_region(&region_str)?,
+        size: parser::parse_size(&size_str)?,
+        rotation: parser::parse_rotation(&rotation_str)?,
+        quality: parser::parse_quality(quality_str)?,
+        format: parser::parse_format(format_str)?,
+    };
+
+    let path = state
+        .resolver
+        .resolve(&identifier)
+        .await
+        .ok_or_else(|| IiifError::NotFound { identifier: identifier.clone() })?;
+    let version = state.resolver.source_version(&path).await.unwrap_or_default();
+
+    let content_type = req.format.mime_type();
+    // Renders made under a watermark policy are cached apart from unmarked ones
+    let watermark_tag = state.config.watermark_for(&identifier).map(|w| w.cache_tag()).unwrap_or_default();
+    // Likewise custom qualities, so editing their steps needs no purge
+    let quality_tag = match &req.quality {
+        Quality::Custom(name) => format!("{:?}", state.config.qualities.get(name)),
+        _ => String::new(),
+    };
+    let cache_params = format!(
+        "{}/{}/{}/{}.{}{}{}",
+        region_str, size_str, rotation_str, quality_str, format_str, watermark_tag, quality_tag
+    );
+    let cache_key = TileCache::get_key(&identifier, &version, &cache_params);
+
+    let data = state
+        .cache
+        .get_or_render(&identifier, &cache_key, || async {
+            let source = source_info(&state, &identifier, &path, version).await?;
+            // Reject bad regions and sizes before a worker is tied up
+            let rect = req.region.resolve(source.metadata.width, source.metadata.height)?;
+            req.size.resolve(rect.width, rect.height, &state.config.size_limits())?;
+
+            let processor = Arc::clone(&state.processor);
+            let path_str = path.to_string_lossy().into_owned();
+            state
+                .pool
+                .run(move || processor.process_image(&path_str, &req, &source.metadata))
+                .await
+        })
+        .await?;
+    Ok((StatusCode::OK, [(header::CONTENT_TYPE, content_type)], data).into_response())
+}
+
+// Dimensions, pyramid levels and linking properties for a source, read on a
+// worker the first time and served from the info cache until it changes
+async fn source_info(
+    state: &AppState,
+    identifier: &str,
+    path: &std::path::Path,
+    version: String,
+) -> Result<Arc<SourceInfo>, IiifError> {
+    if let Some(info) = state.info.get(identifier, &version).await {
+        return Ok(info);
     }
-    let quality_str = parts[0];
-    let format_str = parts[1];
-
-    let region = parser::parse_region(&region_str);
-    let size = parser::parse_size(&size_str);
-    let rotation = parser::parse_rotation(&rotation_str);
-    let quality = parser::parse_quality(quality_str);
-    let format = parser::parse_format(format_str);
-
-    if let (Some(region), Some(size), Some(rotation), Some(quality), Some(format)) = (region, size, rotation, quality, format) {
-        let req = ImageRequest {
-            identifier: identifier.clone(),
-            region,
-            size,
-            rotation,
-            quality,
-            format,
-        };
-
-        let cache_params = format!("{}/{}/{}/{}.{}", region_str, size_str, rotation_str, quality_str, format_str);
-        let cache_key = TileCache::get_key(&identifier, &cache_params);
-
-        if let Some(cached_data) = state.cache.get(&cache_key).await {
-            tracing::debug!("Cache hit for {}", cache_key);
-            return (StatusCode::OK, [("content-type", format!("image/{}", format_str))], cached_data).into_response();
-        }
-
-        match state.resolver.resolve(&identifier).await {
-            Some(path) => {
-                let path_str = path.to_string_lossy();
-                match state.processor.process_image(&path_str, &req) {
-                    Ok(data) => {
-                        state.cache.set(&cache_key, data.clone()).await;
-                        (StatusCode::OK, [("content-type", format!("image/{}", format_str))], data).into_response()
-                    }
-                    Err(e) => {
-                        tracing::error!("Image processing error: {:?}", e);
-                        (StatusCode::INTERNAL_SERVER_ERROR, "Image processing failed").into_response()
-                    }
-                }
-            }
-            None => (StatusCode::NOT_FOUND, "Image not found").into_response(),
-        }
-    } else {
-        (StatusCode::BAD_REQUEST, "Invalid IIIF parameters").into_response()
+    // Every page shares the document's page count, so once the document is
+    // cached a missing page is refused without a trip to the worker pool
+    if let Some((base, _)) = identifier.split_once(":page:")
+        && let Some(document) = state.info.get(base, &version).await
+        && !pyramid::page_exists(identifier, document.metadata.pages)
+    {
+        return Err(IiifError::NotFound { identifier: identifier.to_string() });
     }
+
+    let processor = Arc::clone(&state.processor);
+    let path_str = path.to_string_lossy().into_owned();
+    let source_dir = state.config.iiif.source_dir.clone();
+    let id = identifier.to_string();
+    let (metadata, linking) = state
+        .pool
+        .run(move || {
+            let meta = processor.get_image_metadata(&path_str, &id)?;
+            Ok((meta, metadata::load_linking_properties(&source_dir, &id)))
+        })
+        .await?;
+    let info = Arc::new(SourceInfo { version, metadata, linking });
+    state.info.insert(identifier, Arc::clone(&info)).await;
+    Ok(info)
 }
@@ -0,0 +1,51 @@
+use crate::iiif::info::LinkingProperties;
+use config::{File, FileFormat};
+use std::path::{Component, Path};
+
+// Per-directory defaults, applied to every image at or below that directory
+const DEFAULTS_NAME: &str = "_defaults";
+
+/// Loads the linking properties for `identifier` from files under
+/// `source_dir`. Layers are applied from least to most specific:
+///
+/// 1. `_defaults.json` / `_defaults.toml` in `source_dir` and each directory
+///    down to the image's own directory
+/// 2. a sidecar next to the image, e.g. `foo.tif.json` or `foo.tif.toml`
+///
+/// A key set in a more specific file replaces the same key from a broader one.
+pub fn load_linking_properties(source_dir: &str, identifier: &str) -> LinkingProperties {
+    let base_id = identifier.split_once(":page:").map_or(identifier, |(base, _)| base);
+    let relative = Path::new(base_id);
+    if relative.components().any(|c| !matches!(c, Component::Normal(_))) {
+        return LinkingProperties::default();
+    }
+
+    let root = Path::new(source_dir);
+    let mut builder = config::Config::builder();
+    let mut dir = root.to_path_buf();
+    builder = add_layer(builder, &dir.join(DEFAULTS_NAME).to_string_lossy());
+    if let Some(parent) = relative.parent() {
+        for component in parent.components() {
+            dir.push(component);
+            builder = add_layer(builder, &dir.join(DEFAULTS_NAME).to_string_lossy());
+        }
+    }
+    builder = add_layer(builder, &root.join(relative).to_string_lossy());
+
+    match builder.build().and_then(|settings| settings.try_deserialize()) {
+        Ok(properties) => properties,
+        Err(e) => {
+            tracing::warn!("Ignoring invalid metadata for {}: {}", identifier, e);
+            LinkingProperties::default()
+        }
+    }
+}
+
+fn add_layer(
+    builder: config::ConfigBuilder<config::builder::DefaultState>,
+    base: &str,
+) -> config::ConfigBuilder<config::builder::DefaultState> {
+    builder
+        .add_source(File::new(&format!("{}.json", base), FileFormat::Json).required(false))
+        .add_source(File::new(&format!("{}.toml", base), FileFormat::Toml).required(false))
+}
@@ -0,0 +1,41 @@
+use libvips_rs::bindings;
+use std::ffi::CString;
+use std::sync::OnceLock;
+use crate::iiif::types::Format;
+
+static JPG: OnceLock<bool> = OnceLock::new();
+static PNG: OnceLock<bool> = OnceLock::new();
+static TIF: OnceLock<bool> = OnceLock::new();
+static WEBP: OnceLock<bool> = OnceLock::new();
+static GIF: OnceLock<bool> = OnceLock::new();
+static AVIF: OnceLock<bool> = OnceLock::new();
+static JP2: OnceLock<bool> = OnceLock::new();
+static JXL: OnceLock<bool> = OnceLock::new();
+
+/// Whether the linked libvips can encode `format`. Every saver depends on
+/// a library libvips may have been built without (GIF needs cgif, TIFF
+/// libtiff, AVIF libheif, ...), so each is probed once at runtime. PDF
+/// output wraps a JPEG and needs only the JPEG saver.
+pub fn can_encode(format: &Format) -> bool {
+    match format {
+        Format::Jpg | Format::Pdf => *JPG.get_or_init(|| has_saver(".jpg")),
+        Format::Png => *PNG.get_or_init(|| has_saver(".png")),
+        Format::Tif => *TIF.get_or_init(|| has_saver(".tif")),
+        Format::Webp => *WEBP.get_or_init(|| has_saver(".webp")),
+        Format::Gif => *GIF.get_or_init(|| has_saver(".gif")),
+        Format::Avif => *AVIF.get_or_init(|| has_saver(".avif")),
+        Format::Jp2 => *JP2.get_or_init(|| has_saver(".jp2")),
+        Format::Jxl => *JXL.get_or_init(|| has_saver(".jxl")),
+    }
+}
+
+fn has_saver(suffix: &str) -> bool {
+    let suffix = CString::new(suffix).expect("suffix contains no NUL");
+    // SAFETY: the suffix outlives the call, and libvips only returns a static
+    // type name or NULL. A miss also sets the libvips error buffer, so clear it.
+    unsafe {
+        let found = !bindings::vips_foreign_find_save_buffer(suffix.as_ptr()).is_null();
+        bindings::vips_error_clear();
+        found
+    }
+}
@@ -0,0 +1,42 @@
+use libvips_rs::{ops, VipsImage};
+use crate::iiif::error::IiifError;
+
+/// Converts colour images to `output_profile`, a libvips built-in profile
+/// name ("srgb", "p3", ...) or an ICC file path. An embedded profile is used
+/// when present; untagged CMYK is assumed to be generic CMYK and any other
+/// untagged colour image to be sRGB. Gray images are left as-is.
+pub fn to_output_profile(img: VipsImage, output_profile: &str) -> Result<VipsImage, libvips_rs::error::Error> {
+    let input_profile = match img.get_interpretation()? {
+        ops::Interpretation::Cmyk => "cmyk",
+        _ if img.get_bands() >= 3 => "srgb",
+        _ => return Ok(img),
+    };
+    ops::icc_transform_with_opts(&img, output_profile, &ops::IccTransformOptions {
+        embedded: true,
+        input_profile: input_profile.to_string(),
+        // Keep 16-bit sources deep; encoders that need 8 bits reduce them later
+        depth: if matches!(img.get_format()?, ops::BandFormat::Ushort) { 16 } else { 8 },
+        ..Default::default()
+    })
+}
+
+/// Reduces images with more than four bands (e.g. multispectral captures) to
+/// the configured `display_bands`, shown as RGB. CMYK with alpha is kept.
+/// Fails when the image has fewer bands than `display_bands` names.
+pub fn select_display_bands(img: VipsImage, display_bands: &[u32]) -> Result<VipsImage, IiifError> {
+    let bands = img.get_bands();
+    if bands <= 4 || matches!(img.get_interpretation()?, ops::Interpretation::Cmyk) {
+        return Ok(img);
+    }
+    if let Some(band) = display_bands.iter().find(|&&band| band as i32 >= bands) {
+        return Err(IiifError::Processing(format!(
+            "display band {} is out of range for an image with {} bands",
+            band, bands
+        )));
+    }
+    let mut selected = display_bands
+        .iter()
+        .map(|&band| ops::extract_band(&img, band as i32))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(ops::bandjoin(&mut selected)?)
+}
@@ -0,0 +1,183 @@
+use libvips_rs::{ops, VipsImage};
+use crate::config::{EncodingConfig, EncodingOptions, JpegOptions, PngOptions, Subsampling, WebpOptions};
+use crate::iiif::types::Format;
+use crate::processor::pdf;
+
+/// Encodes `img` in the requested output `format` using the encoder
+/// `options`, copying only the source metadata `encoding` allows
+pub fn encode(
+    img: VipsImage,
+    format: &Format,
+    options: &EncodingOptions,
+    encoding: &EncodingConfig,
+) -> Result<Vec<u8>, libvips_rs::error::Error> {
+    let keep = encoding.keep();
+    let prepared = prepare(img, format, &encoding.background)?;
+    let img = &prepared;
+    match format {
+        Format::Jpg => ops::jpegsave_buffer_with_opts(img, &jpeg_options(&options.jpeg, keep)),
+        Format::Png => ops::pngsave_buffer_with_opts(img, &png_options(&options.png, keep)),
+        Format::Webp => ops::webpsave_buffer_with_opts(img, &webp_options(&options.webp, keep)),
+        Format::Tif => ops::tiffsave_buffer_with_opts(img, &ops::TiffsaveBufferOptions {
+            keep,
+            ..Default::default()
+        }),
+        Format::Gif => ops::gifsave_buffer_with_opts(img, &ops::GifsaveBufferOptions {
+            keep,
+            ..Default::default()
+        }),
+        Format::Pdf => pdf::pdfsave_buffer(img, &jpeg_options(&options.jpeg, ops::ForeignKeep::None)),
+        Format::Avif => ops::heifsave_buffer_with_opts(img, &ops::HeifsaveBufferOptions {
+            compression: ops::ForeignHeifCompression::Av1,
+            bitdepth: 8,
+            keep,
+            ..Default::default()
+        }),
+        Format::Jp2 => img.image_write_to_buffer(&format!(".jp2[keep={}]", keep_name(keep))),
+        Format::Jxl => img.image_write_to_buffer(&format!(".jxl[keep={}]", keep_name(keep))),
+    }
+}
+
+// Brings `img` within what the encoder for `format` can store: 8 bits per
+// band where deeper images are unsupported (16 at most elsewhere), and no
+// alpha for JPEG and PDF
+fn prepare(img: VipsImage, format: &Format, background: &[f64]) -> Result<VipsImage, libvips_rs::error::Error> {
+    let img = match format {
+        Format::Png | Format::Tif | Format::Jp2 | Format::Jxl => to_integer_depth(img)?,
+        _ => to_8bit(img)?,
+    };
+    if supports_alpha(format) || !img.image_hasalpha() {
+        return Ok(img);
+    }
+    ops::flatten_with_opts(&img, &ops::FlattenOptions {
+        background: background_for(img.get_bands() - 1, background),
+        ..Default::default()
+    })
+}
+
+/// Whether `format` can store transparency
+pub fn supports_alpha(format: &Format) -> bool {
+    !matches!(format, Format::Jpg | Format::Pdf)
+}
+
+/// The configured `background` for an image with `color_bands` colour
+/// bands; gray images take the average as their single value
+pub fn background_for(color_bands: i32, background: &[f64]) -> Vec<f64> {
+    if color_bands == 1 && background.len() > 1 {
+        vec![background.iter().sum::<f64>() / background.len() as f64]
+    } else {
+        background.to_vec()
+    }
+}
+
+/// Reduces deeper images to 8 bits per band
+pub fn to_8bit(img: VipsImage) -> Result<VipsImage, libvips_rs::error::Error> {
+    let img = to_integer_depth(img)?;
+    match img.get_format()? {
+        // Keep the high byte, so 16-bit ranges map onto 0-255
+        ops::BandFormat::Ushort => ops::cast_with_opts(&img, ops::BandFormat::Uchar, &ops::CastOptions { shift: true }),
+        _ => Ok(img),
+    }
+}
+
+/// Brings float, signed and 32-bit images to 8 or 16 bits per band by
+/// mapping each format's range onto the integer range rather than clipping
+/// to it, so colour management and every encoder see uchar or ushort data.
+pub fn to_integer_depth(img: VipsImage) -> Result<VipsImage, libvips_rs::error::Error> {
+    match img.get_format()? {
+        ops::BandFormat::Uchar | ops::BandFormat::Ushort => Ok(img),
+        ops::BandFormat::Float | ops::BandFormat::Double => match img.get_interpretation()? {
+            // Linear-light and device-independent spaces are converted (and
+            // gamma-encoded) by libvips itself
+            ops::Interpretation::Scrgb
+            | ops::Interpretation::Xyz
+            | ops::Interpretation::Lab
+            | ops::Interpretation::Lch
+            | ops::Interpretation::Cmc
+            | ops::Interpretation::Yxy
+            | ops::Interpretation::Oklab => ops::colourspace(&img, ops::Interpretation::Rgb16),
+            // Other float masters (TIFF, FITS, PFM) hold values normalised to 0-1
+            _ => rescale(&img, 1.0, ops::BandFormat::Ushort),
+        },
+        // Signed and 32-bit integers: the non-negative range maps onto the output
+        ops::BandFormat::Char => rescale(&img, i8::MAX as f64, ops::BandFormat::Uchar),
+        ops::BandFormat::Short => rescale(&img, i16::MAX as f64, ops::BandFormat::Ushort),
+        ops::BandFormat::Uint => rescale(&img, u32::MAX as f64, ops::BandFormat::Ushort),
+        ops::BandFormat::Int => rescale(&img, i32::MAX as f64, ops::BandFormat::Ushort),
+        _ => ops::cast(&img, ops::BandFormat::Uchar),
+    }
+}
+
+// Maps 0..=max onto the full range of `format` (uchar or ushort), clipping
+// anything outside, and tags 16-bit results as RGB16/GREY16 so later colour
+// conversions read them with the right range
+fn rescale(img: &VipsImage, max: f64, format: ops::BandFormat) -> Result<VipsImage, libvips_rs::error::Error> {
+    let deep = matches!(format, ops::BandFormat::Ushort);
+    let top = if deep { 65535.0 } else { 255.0 };
+    let scaled = ops::cast(&ops::linear(img, &mut [top / max], &mut [0.0])?, format)?;
+    let interpretation = match img.get_interpretation()? {
+        ops::Interpretation::Srgb | ops::Interpretation::Rgb if deep => ops::Interpretation::Rgb16,
+        ops::Interpretation::BW if deep => ops::Interpretation::Grey16,
+        interpretation => interpretation,
+    };
+    ops::copy_with_opts(&scaled, &ops::CopyOptions {
+        width: scaled.get_width(),
+        height: scaled.get_height(),
+        bands: scaled.get_bands(),
+        format,
+        coding: scaled.get_coding()?,
+        interpretation,
+        xres: scaled.get_xres(),
+        yres: scaled.get_yres(),
+        xoffset: scaled.get_xoffset(),
+        yoffset: scaled.get_yoffset(),
+    })
+}
+
+// Option-string spelling of the `keep` flags
+fn keep_name(keep: ops::ForeignKeep) -> &'static str {
+    match keep {
+        ops::ForeignKeep::None => "none",
+        ops::ForeignKeep::Exif => "exif",
+        ops::ForeignKeep::Xmp => "xmp",
+        ops::ForeignKeep::Iptc => "iptc",
+        ops::ForeignKeep::Icc => "icc",
+        ops::ForeignKeep::Other => "other",
+        ops::ForeignKeep::Gainmap => "gainmap",
+        ops::ForeignKeep::All => "all",
+    }
+}
+
+fn jpeg_options(jpeg: &JpegOptions, keep: ops::ForeignKeep) -> ops::JpegsaveBufferOptions {
+    let defaults = ops::JpegsaveBufferOptions::default();
+    ops::JpegsaveBufferOptions {
+        q: jpeg.quality.unwrap_or(defaults.q),
+        interlace: jpeg.progressive.unwrap_or(defaults.interlace),
+        subsample_mode: match jpeg.subsampling {
+            Some(Subsampling::Auto) | None => ops::ForeignSubsample::Auto,
+            Some(Subsampling::On) => ops::ForeignSubsample::On,
+            Some(Subsampling::Off) => ops::ForeignSubsample::Off,
+        },
+        keep,
+        ..defaults
+    }
+}
+
+fn png_options(png: &PngOptions, keep: ops::ForeignKeep) -> ops::PngsaveBufferOptions {
+    let defaults = ops::PngsaveBufferOptions::default();
+    ops::PngsaveBufferOptions {
+        compression: png.compression.unwrap_or(defaults.compression),
+        keep,
+        ..defaults
+    }
+}
+
+fn webp_options(webp: &WebpOptions, keep: ops::ForeignKeep) -> ops::WebpsaveBufferOptions {
+    let defaults = ops::WebpsaveBufferOptions::default();
+    ops::WebpsaveBufferOptions {
+        q: webp.quality.unwrap_or(defaults.q),
+        lossless: webp.lossless.unwrap_or(defaults.lossless),
+        keep,
+        ..defaults
+    }
+}
@@ -1,121 +1,128 @@
+pub mod codecs;
+pub mod color;
+pub mod encode;
+pub mod orientation;
 pub mod pdf;
+pub mod pool;
+pub mod pyramid;
+pub mod quality;
+pub mod rotation;
+pub mod watermark;
 
-use libvips_rs::{ops, VipsImage, VipsApp};
+use libvips_rs::{ops, VipsApp};
+use crate::config::Config;
+use crate::iiif::error::IiifError;
 use crate::iiif::types::*;
 use std::sync::Once;
 
 static START: Once = Once::new();
 
+/// Source properties needed to describe an image and plan its renders
+#[derive(Debug, Clone)]
+pub struct ImageMetadata {
+    /// Dimensions as displayed, after applying the EXIF orientation
+    pub width: u32,
+    pub height: u32,
+    /// EXIF orientation of the source (1 when upright or untagged)
+    pub orientation: i32,
+    /// Reduced-resolution pyramid levels, largest first
+    pub levels: Vec<pyramid::Level>,
+    /// Pages in the source document (1 unless it is a PDF)
+    pub pages: u32,
+}
+
 pub struct ImageProcessor {
     _app: VipsApp,
+    config: Config,
 }
 
 impl ImageProcessor {
-    pub fn new() -> Self {
+    pub fn new(config: Config) -> Self {
         START.call_once(|| {
             // Initialization
         });
         Self {
             _app: VipsApp::new("iiif-processor", false).expect("Failed to init libvips"),
+            config,
         }
     }
 
-    pub fn get_image_size(&self, path: &str, identifier: &str) -> Result<(i32, i32), libvips_rs::error::Error> {
-        let img = if path.to_lowercase().ends_with(".pdf") {
-            if let Some((_, page_str)) = identifier.split_once(":page:") {
-                let page: i32 = page_str.parse().unwrap_or(0);
-                pdf::load_pdf_page(path, page)?
-            } else {
-                pdf::load_pdf_page(path, 0)?
+    pub fn get_image_metadata(&self, path: &str, identifier: &str) -> Result<ImageMetadata, IiifError> {
+        // Checked before the page is opened, as libvips fails on pages past the end
+        let pages = pyramid::page_count(path)?;
+        if !pyramid::page_exists(identifier, pages) {
+            return Err(IiifError::NotFound { identifier: identifier.to_string() });
+        }
+        let img = pyramid::open_full(path, identifier)?;
+        let orientation = orientation::normalize(img.get_orientation());
+        let (mut width, mut height) = (img.get_width() as u32, img.get_height() as u32);
+        let mut levels = pyramid::levels(path, &img);
+        if orientation::swaps_dimensions(orientation) {
+            std::mem::swap(&mut width, &mut height);
+            for level in &mut levels {
+                std::mem::swap(&mut level.width, &mut level.height);
             }
-        } else {
-            VipsImage::new_from_file(path)?
-        };
-        Ok((img.get_width(), img.get_height()))
+        }
+        Ok(ImageMetadata { width, height, orientation, levels, pages })
     }
 
-    pub fn process_image(&self, path: &str, req: &ImageRequest) -> Result<Vec<u8>, libvips_rs::error::Error> {
-        let img = if path.to_lowercase().ends_with(".pdf") {
-            if let Some((_, page_str)) = req.identifier.split_once(":page:") {
-                let page: i32 = page_str.parse().unwrap_or(0);
-                pdf::load_pdf_page(path, page)?
-            } else {
-                pdf::load_pdf_page(path, 0)?
-            }
-        } else {
-            VipsImage::new_from_file(path)?
-        };
-        
-        // 1. Region
-        let img = match req.region {
-            Region::Full => img,
-            Region::Square => {
-                let size = img.get_width().min(img.get_height());
-                let x = (img.get_width() - size) / 2;
-                let y = (img.get_height() - size) / 2;
-                ops::extract_area(&img, x, y, size, size)?
-            },
-            Region::Absolute(x, y, w, h) => ops::extract_area(&img, x as i32, y as i32, w as i32, h as i32)?,
-            Region::Percentage(px, py, pw, ph) => {
-                let x = (img.get_width() as f64 * px / 100.0) as i32;
-                let y = (img.get_height() as f64 * py / 100.0) as i32;
-                let w = (img.get_width() as f64 * pw / 100.0) as i32;
-                let h = (img.get_height() as f64 * ph / 100.0) as i32;
-                ops::extract_area(&img, x, y, w, h)?
-            }
-        };
+    /// Renders `req` from the source at `path`, whose dimensions and pyramid
+    /// levels were previously read into `meta`.
+    pub fn process_image(&self, path: &str, req: &ImageRequest, meta: &ImageMetadata) -> Result<Vec<u8>, IiifError> {
+        let (full_w, full_h) = (meta.width, meta.height);
 
-        // 2. Size
-        let img = match req.size {
-            Size::Max => img,
-            Size::Width(w) => {
-                let scale = w as f64 / img.get_width() as f64;
-                ops::resize(&img, scale)?
-            },
-            Size::Height(h) => {
-                let scale = h as f64 / img.get_height() as f64;
-                ops::resize(&img, scale)?
-            },
-            Size::WidthHeight(w, h) => {
-                let h_scale = h as f64 / img.get_height() as f64;
-                let w_scale = w as f64 / img.get_width() as f64;
-                ops::resize_with_opts(&img, w_scale, &ops::ResizeOptions {
-                    vscale: h_scale,
-                    ..Default::default()
-                })?
-            },
-            _ => img 
-        };
+        // 1. Resolve region and size against the full-resolution dimensions
+        let rect = req.region.resolve(full_w, full_h)?;
+        let (w, h) = req.size.resolve(rect.width, rect.height, &self.config.size_limits())?;
+        let scale = (w as f64 / rect.width as f64, h as f64 / rect.height as f64);
 
-        // 3. Rotation
-        let img = if req.rotation.mirror {
-            ops::flip(&img, ops::Direction::Horizontal)?
-        } else {
+        // 2. Reopen at the smallest level that still covers the output, upright
+        let shrink = (rect.width as f64 / w as f64).min(rect.height as f64 / h as f64);
+        let img = pyramid::open_reduced(path, &req.identifier, &meta.levels, full_w, shrink)?;
+        let img = orientation::apply(img, meta.orientation)?;
+        let (img_w, img_h) = (img.get_width() as u32, img.get_height() as u32);
+        let rect = rect.scale(img_w as f64 / full_w as f64, img_h as f64 / full_h as f64, img_w, img_h);
+
+        // 3. Region
+        let img = if rect.is_full(img_w, img_h) {
             img
-        };
-        let img = if req.rotation.degrees != 0.0 {
-            ops::rotate(&img, req.rotation.degrees)?
         } else {
+            ops::extract_area(&img, rect.x as i32, rect.y as i32, rect.width as i32, rect.height as i32)?
+        };
+
+        // 4. Size
+        let img = if (w, h) == (rect.width, rect.height) {
             img
+        } else {
+            let w_scale = w as f64 / rect.width as f64;
+            let h_scale = h as f64 / rect.height as f64;
+            ops::resize_with_opts(&img, w_scale, &ops::ResizeOptions {
+                vscale: h_scale,
+                ..Default::default()
+            })?
         };
 
-        // 4. Quality
-        let img = match req.quality {
-            Quality::Default | Quality::Color => img,
-            Quality::Gray => ops::colourspace(&img, ops::Interpretation::BW)?,
-            Quality::Bitonal => {
-                let gray = ops::colourspace(&img, ops::Interpretation::BW)?;
-                let mut threshold = [128.0];
-                ops::relational_const(&gray, ops::OperationRelational::More, &mut threshold)?
-            }
+        // 5. Colour: bring the samples to 8 or 16 bits, pick display bands,
+        // honour embedded profiles and convert to the output profile
+        let img = encode::to_integer_depth(img)?;
+        let img = color::select_display_bands(img, &self.config.encoding.display_bands)?;
+        let img = color::to_output_profile(img, &self.config.encoding.output_profile)?;
+
+        // 6. Rotation
+        let img = rotation::apply(img, &req.rotation, &req.format, &self.config.encoding.background)?;
+
+        // 7. Quality
+        let img = quality::apply(img, &req.quality, &self.config.quality, &self.config.qualities)?;
+
+        // 8. Watermark
+        let img = match self.config.watermark_for(&req.identifier) {
+            Some(watermark) => watermark::apply(img, watermark, meta, scale)?,
+            None => img,
         };
 
-        // 5. Format and Output
-        match req.format {
-            Format::Jpg => ops::jpegsave_buffer(&img),
-            Format::Png => ops::pngsave_buffer(&img),
-            Format::Webp => ops::webpsave_buffer(&img),
-            _ => ops::jpegsave_buffer(&img)
-        }
+        // 9. Format and Output
+        let options = self.config.encoding_for(&req.identifier);
+        let data = encode::encode(img, &req.format, &options, &self.config.encoding)?;
+        Ok(data)
     }
 }
@@ -0,0 +1,44 @@
+use libvips_rs::{ops, VipsImage};
+
+/// Normalises an EXIF orientation tag, treating missing or invalid values
+/// as upright
+pub fn normalize(orientation: i32) -> i32 {
+    if (1..=8).contains(&orientation) { orientation } else { 1 }
+}
+
+/// Whether displaying an image with this orientation swaps width and height
+pub fn swaps_dimensions(orientation: i32) -> bool {
+    (5..=8).contains(&orientation)
+}
+
+/// Rotates and flips `img` so it is displayed upright. The orientation comes
+/// from the full-resolution source, since reduced levels may not carry it.
+pub fn apply(img: VipsImage, orientation: i32) -> Result<VipsImage, libvips_rs::error::Error> {
+    if orientation == 1 {
+        return Ok(img);
+    }
+    // autorot also drops the tag, so viewers don't rotate the output again
+    if img.get_orientation() == orientation {
+        return ops::autorot(&img);
+    }
+
+    let (angle, flip) = match orientation {
+        2 => (ops::Angle::D0, true),
+        3 => (ops::Angle::D180, false),
+        4 => (ops::Angle::D180, true),
+        5 => (ops::Angle::D90, true),
+        6 => (ops::Angle::D90, false),
+        7 => (ops::Angle::D270, true),
+        8 => (ops::Angle::D270, false),
+        _ => (ops::Angle::D0, false),
+    };
+    let img = match angle {
+        ops::Angle::D0 => img,
+        angle => ops::rot(&img, angle)?,
+    };
+    if flip {
+        ops::flip(&img, ops::Direction::Horizontal)
+    } else {
+        Ok(img)
+    }
+}
@@ -1,7 +1,79 @@
-use libvips_rs::VipsImage;
+use libvips_rs::{ops, VipsImage};
+
+/// libvips pdfload renders at 72 DPI unless told otherwise
+pub const DEFAULT_DPI: f64 = 72.0;
 
 pub fn load_pdf_page(path: &str, page: i32) -> Result<VipsImage, libvips_rs::error::Error> {
     // libvips pdfload supports [page=N] suffix
     let path_with_page = format!("{}[page={}]", path, page);
     VipsImage::new_from_file(&path_with_page)
 }
+
+pub fn load_pdf_page_at_dpi(path: &str, page: i32, dpi: f64) -> Result<VipsImage, libvips_rs::error::Error> {
+    let path_with_opts = format!("{}[page={},dpi={}]", path, page, dpi);
+    VipsImage::new_from_file(&path_with_opts)
+}
+
+/// Encodes `img` as a single-page PDF. The pixels are JPEG-compressed and
+/// embedded unchanged as a DCTDecode image filling a page sized at 72 DPI.
+pub fn pdfsave_buffer(
+    img: &VipsImage,
+    jpeg_options: &ops::JpegsaveBufferOptions,
+) -> Result<Vec<u8>, libvips_rs::error::Error> {
+    // DCTDecode images are either gray or RGB; jpegsave drops any alpha band
+    let gray = img.get_bands() <= 2;
+    let space = if gray { ops::Interpretation::BW } else { ops::Interpretation::Srgb };
+    let img = ops::colourspace(img, space)?;
+    let jpeg = ops::jpegsave_buffer_with_opts(&img, jpeg_options)?;
+    Ok(wrap_jpeg(&jpeg, img.get_width(), img.get_height(), gray))
+}
+
+// Writes the smallest valid PDF around one JPEG: catalog, page tree, page,
+// image XObject and a content stream that scales the image to the page
+fn wrap_jpeg(jpeg: &[u8], width: i32, height: i32, gray: bool) -> Vec<u8> {
+    let color_space = if gray { "DeviceGray" } else { "DeviceRGB" };
+    let content = format!("q {} 0 0 {} 0 0 cm /Im0 Do Q", width, height);
+    let objects: Vec<Vec<u8>> = vec![
+        b"<< /Type /Catalog /Pages 2 0 R >>".to_vec(),
+        b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>".to_vec(),
+        format!(
+            "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /XObject << /Im0 4 0 R >> >> /Contents 5 0 R >>",
+            width, height
+        )
+        .into_bytes(),
+        stream(
+            &format!(
+                "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace /{} /BitsPerComponent 8 /Filter /DCTDecode",
+                width, height, color_space
+            ),
+            jpeg,
+        ),
+        stream("", content.as_bytes()),
+    ];
+
+    let mut pdf = b"%PDF-1.4\n%\xE2\xE3\xCF\xD3\n".to_vec();
+    let mut offsets = Vec::with_capacity(objects.len());
+    for (i, body) in objects.iter().enumerate() {
+        offsets.push(pdf.len());
+        pdf.extend_from_slice(format!("{} 0 obj\n", i + 1).as_bytes());
+        pdf.extend_from_slice(body);
+        pdf.extend_from_slice(b"\nendobj\n");
+    }
+
+    let xref = pdf.len();
+    pdf.extend_from_slice(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        pdf.extend_from_slice(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    pdf.extend_from_slice(
+        format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes(),
+    );
+    pdf
+}
+
+fn stream(dict: &str, data: &[u8]) -> Vec<u8> {
+    let mut out = format!("<< {} /Length {} >>\nstream\n", dict, data.len()).into_bytes();
+    out.extend_from_slice(data);
+    out.extend_from_slice(b"\nendstream");
+    out
+}
@@ -0,0 +1,87 @@
+use crate::iiif::error::IiifError;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// Fixed set of OS threads dedicated to libvips work, so long renders never
+/// occupy tokio's async workers. Jobs beyond `queue_limit` are rejected
+/// rather than queued indefinitely.
+pub struct WorkerPool {
+    sender: SyncSender<Job>,
+    timeout: Duration,
+}
+
+impl WorkerPool {
+    pub fn new(workers: usize, queue_limit: usize, timeout: Duration) -> Self {
+        let (sender, receiver) = mpsc::sync_channel::<Job>(queue_limit);
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..workers.max(1) {
+            let receiver = Arc::clone(&receiver);
+            thread::Builder::new()
+                .name(format!("iiif-worker-{}", i))
+                .spawn(move || worker_loop(receiver))
+                .expect("Failed to spawn image worker");
+        }
+
+        Self { sender, timeout }
+    }
+
+    /// Runs `f` on the pool, failing with `Overloaded` if the queue is full
+    /// and `Timeout` if no result arrives within the configured limit.
+    pub async fn run<T, F>(&self, f: F) -> Result<T, IiifError>
+    where
+        F: FnOnce() -> Result<T, IiifError> + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            // The caller already gave up; skip work that nobody will read
+            if tx.is_closed() {
+                return;
+            }
+            let _ = tx.send(f());
+        });
+
+        match self.sender.try_send(job) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                tracing::warn!("Image worker queue is full, rejecting request");
+                return Err(IiifError::Overloaded);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                return Err(IiifError::Processing("Image worker pool has shut down".to_string()));
+            }
+        }
+
+        match tokio::time::timeout(self.timeout, rx).await {
+            Ok(Ok(result)) => result,
+            Ok(Err(_)) => Err(IiifError::Processing("Image worker panicked".to_string())),
+            Err(_) => {
+                tracing::warn!("Image processing exceeded {:?}", self.timeout);
+                Err(IiifError::Timeout)
+            }
+        }
+    }
+}
+
+fn worker_loop(receiver: Arc<Mutex<Receiver<Job>>>) {
+    loop {
+        let job = match receiver.lock() {
+            Ok(rx) => rx.recv(),
+            Err(_) => return,
+        };
+        match job {
+            // Keep the worker alive if a render panics; the caller sees a dropped sender
+            Ok(job) => {
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            }
+            Err(_) => return,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use libvips_rs::VipsImage;
+use crate::processor::pdf;
+
+// Upper bound on SubIFD levels probed in a TIFF
+const MAX_SUBIFD_LEVELS: i32 = 16;
+// PDFs are never rasterised above this resolution
+const MAX_PDF_DPI: f64 = 600.0;
+
+enum SourceKind {
+    Jpeg,
+    Tiff,
+    Webp,
+    Pdf,
+    Other,
+}
+
+fn source_kind(path: &str) -> SourceKind {
+    let lower = path.to_lowercase();
+    if lower.ends_with(".jpg") || lower.ends_with(".jpeg") {
+        SourceKind::Jpeg
+    } else if lower.ends_with(".tif") || lower.ends_with(".tiff") {
+        SourceKind::Tiff
+    } else if lower.ends_with(".webp") {
+        SourceKind::Webp
+    } else if lower.ends_with(".pdf") {
+        SourceKind::Pdf
+    } else {
+        SourceKind::Other
+    }
+}
+
+/// A reduced-resolution level stored in the source file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Level {
+    pub width: u32,
+    pub height: u32,
+    options: String,
+}
+
+/// Page number requested via the `:page:N` identifier suffix
+pub fn pdf_page(identifier: &str) -> i32 {
+    identifier
+        .split_once(":page:")
+        .and_then(|(_, page)| page.parse().ok())
+        .unwrap_or(0)
+}
+
+/// Whether the `:page:N` suffix of `identifier`, if any, names one of the
+/// `pages` in the source. Malformed page numbers never match.
+pub fn page_exists(identifier: &str, pages: u32) -> bool {
+    match identifier.split_once(":page:") {
+        Some((_, page)) => page.parse::<u32>().is_ok_and(|page| page < pages),
+        None => true,
+    }
+}
+
+/// Number of pages in the source: the document length for PDFs, read from
+/// the first page's header, and 1 for everything else.
+pub fn page_count(path: &str) -> Result<u32, libvips_rs::error::Error> {
+    match source_kind(path) {
+        SourceKind::Pdf => Ok(pdf::load_pdf_page(path, 0)?.get_n_pages().max(1) as u32),
+        _ => Ok(1),
+    }
+}
+
+/// Opens the source at full resolution. libvips only reads the header here;
+/// pixels are decoded lazily by later operations.
+pub fn open_full(path: &str, identifier: &str) -> Result<VipsImage, libvips_rs::error::Error> {
+    match source_kind(path) {
+        SourceKind::Pdf => pdf::load_pdf_page(path, pdf_page(identifier)),
+        _ => VipsImage::new_from_file(path),
+    }
+}
+
+/// Opens the source at the cheapest resolution that is still at least
+/// `1 / shrink` of the full size, using pyramid `levels`, JPEG DCT scaling,
+/// WebP scaled decoding or the PDF render DPI as available, and falls back
+/// to the full-resolution image otherwise.
+pub fn open_reduced(
+    path: &str,
+    identifier: &str,
+    levels: &[Level],
+    full_width: u32,
+    shrink: f64,
+) -> Result<VipsImage, libvips_rs::error::Error> {
+    match source_kind(path) {
+        SourceKind::Pdf => {
+            // Render at a DPI matching the output, which also sharpens upscaled pages
+            let dpi = (pdf::DEFAULT_DPI / shrink).min(MAX_PDF_DPI);
+            if (dpi - pdf::DEFAULT_DPI).abs() < 1.0 {
+                return open_full(path, identifier);
+            }
+            pdf::load_pdf_page_at_dpi(path, pdf_page(identifier), dpi)
+        }
+        _ if shrink < 2.0 => open_full(path, identifier),
+        SourceKind::Jpeg => {
+            let factor = [8, 4, 2].into_iter().find(|&f| f as f64 <= shrink).unwrap_or(1);
+            VipsImage::new_from_file(&format!("{}[shrink={}]", path, factor))
+        }
+        SourceKind::Webp => VipsImage::new_from_file(&format!("{}[scale={}]", path, 1.0 / shrink)),
+        SourceKind::Tiff => {
+            let best = levels
+                .iter()
+                .filter(|level| full_width as f64 / level.width as f64 <= shrink)
+                .min_by_key(|level| level.width);
+            match best {
+                Some(level) => VipsImage::new_from_file(&format!("{}[{}]", path, level.options)),
+                None => open_full(path, identifier),
+            }
+        }
+        SourceKind::Other => open_full(path, identifier),
+    }
+}
+
+/// Lists the reduced-resolution levels of a pyramidal TIFF, largest first.
+/// Both page-based and SubIFD pyramids are recognised; other formats have none.
+pub fn levels(path: &str, full: &VipsImage) -> Vec<Level> {
+    if !matches!(source_kind(path), SourceKind::Tiff) {
+        return Vec::new();
+    }
+
+    let pages = (1..full.get_n_pages()).map(|page| format!("page={}", page));
+    let levels = collect_levels(path, full, pages);
+    if !levels.is_empty() {
+        return levels;
+    }
+    let subifds = (0..MAX_SUBIFD_LEVELS).map(|subifd| format!("subifd={}", subifd));
+    collect_levels(path, full, subifds)
+}
+
+// Opens each candidate in turn, stopping at the first one that fails to load
+// or is not a smaller copy of the full image (e.g. an unrelated TIFF page)
+fn collect_levels(path: &str, full: &VipsImage, candidates: impl Iterator<Item = String>) -> Vec<Level> {
+    let (full_w, full_h) = (full.get_width() as f64, full.get_height() as f64);
+    let mut levels: Vec<Level> = Vec::new();
+    for options in candidates {
+        let Ok(img) = VipsImage::new_from_file(&format!("{}[{}]", path, options)) else {
+            break;
+        };
+        let (w, h) = (img.get_width() as u32, img.get_height() as u32);
+        let prev_w = levels.last().map_or(full_w as u32, |level| level.width);
+        let expected_h = w as f64 * full_h / full_w;
+        if w >= prev_w || (expected_h - h as f64).abs() > 1.5 {
+            break;
+        }
+        levels.push(Level { width: w, height: h, options });
+    }
+    levels
+}
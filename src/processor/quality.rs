@@ -0,0 +1,188 @@
+use libvips_rs::{ops, VipsImage};
+use crate::config::{BitonalMethod, QualityConfig, QualityStep};
+use crate::processor::encode;
+use std::collections::BTreeMap;
+use crate::iiif::types::Quality;
+
+// 8x8 Bayer matrix for ordered dithering
+const BAYER_8: [u8; 64] = [
+    0, 32, 8, 40, 2, 34, 10, 42,
+    48, 16, 56, 24, 50, 18, 58, 26,
+    12, 44, 4, 36, 14, 46, 6, 38,
+    60, 28, 52, 20, 62, 30, 54, 22,
+    3, 35, 11, 43, 1, 33, 9, 41,
+    51, 19, 59, 27, 49, 17, 57, 25,
+    15, 47, 7, 39, 13, 45, 5, 37,
+    63, 31, 55, 23, 61, 29, 53, 21,
+];
+
+/// Applies the requested IIIF quality, looking custom qualities up in `custom`
+pub fn apply(
+    img: VipsImage,
+    quality: &Quality,
+    config: &QualityConfig,
+    custom: &BTreeMap<String, Vec<QualityStep>>,
+) -> Result<VipsImage, libvips_rs::error::Error> {
+    match quality {
+        Quality::Default | Quality::Color => Ok(img),
+        Quality::Gray => gray(img),
+        Quality::Bitonal => bitonal(gray(img)?, config),
+        Quality::Custom(name) => match custom.get(name) {
+            Some(steps) => custom_steps(img, steps),
+            None => Err(libvips_rs::error::Error::OperationError("Unknown custom quality")),
+        },
+    }
+}
+
+/// Converts to 8-bit gray. Colour luminance is computed in linear light, so
+/// midtones don't darken the way averaging gamma-encoded values does.
+fn gray(img: VipsImage) -> Result<VipsImage, libvips_rs::error::Error> {
+    let color_bands = img.get_bands() - img.image_hasalpha() as i32;
+    if color_bands >= 3 {
+        let linear = ops::s_rgb_2sc_rgb(&img)?;
+        ops::sc_rgb2bw(&linear)
+    } else {
+        ops::colourspace(&img, ops::Interpretation::BW)
+    }
+}
+
+// Reduces the gray band to black and white, keeping any alpha band
+fn bitonal(gray: VipsImage, config: &QualityConfig) -> Result<VipsImage, libvips_rs::error::Error> {
+    let (luma, alpha) = if gray.get_bands() > 1 {
+        (ops::extract_band(&gray, 0)?, Some(ops::extract_band(&gray, 1)?))
+    } else {
+        (gray, None)
+    };
+    let bw = match config.bitonal {
+        BitonalMethod::Threshold => threshold(&luma, config.threshold)?,
+        BitonalMethod::Otsu => threshold(&luma, otsu_threshold(&luma)?)?,
+        BitonalMethod::Adaptive => adaptive(&luma, config.adaptive_radius, config.adaptive_offset)?,
+        BitonalMethod::Dither => dither(&luma)?,
+    };
+    match alpha {
+        Some(alpha) => ops::bandjoin(&mut [bw, alpha]),
+        None => Ok(bw),
+    }
+}
+
+fn threshold(luma: &VipsImage, level: f64) -> Result<VipsImage, libvips_rs::error::Error> {
+    ops::relational_const(luma, ops::OperationRelational::More, &mut [level])
+}
+
+// Otsu's method: the level that maximises the variance between the dark
+// and light classes of the histogram
+fn otsu_threshold(luma: &VipsImage) -> Result<f64, libvips_rs::error::Error> {
+    let counts: Vec<f64> = histogram(luma)?.into_iter().map(|n| n as f64).collect();
+    let total: f64 = counts.iter().sum();
+    let weighted_total: f64 = counts.iter().enumerate().map(|(i, n)| i as f64 * n).sum();
+
+    let (mut best_level, mut best_variance) = (128.0, 0.0);
+    let (mut dark, mut dark_weighted) = (0.0, 0.0);
+    for (level, &count) in counts.iter().enumerate() {
+        dark += count;
+        dark_weighted += level as f64 * count;
+        let light = total - dark;
+        if dark == 0.0 || light == 0.0 {
+            continue;
+        }
+        let mean_dark = dark_weighted / dark;
+        let mean_light = (weighted_total - dark_weighted) / light;
+        let variance = dark * light * (mean_dark - mean_light).powi(2);
+        if variance > best_variance {
+            best_variance = variance;
+            best_level = level as f64;
+        }
+    }
+    Ok(best_level)
+}
+
+// A pixel turns black when it is more than `offset` darker than the
+// gaussian-weighted mean of its surroundings, which copes with uneven
+// fading and lighting across a page
+fn adaptive(luma: &VipsImage, radius: f64, offset: f64) -> Result<VipsImage, libvips_rs::error::Error> {
+    let mean = ops::gaussblur(luma, radius)?;
+    let lifted = ops::add(luma, &VipsImage::new_from_image1(luma, offset)?)?;
+    ops::relational(&lifted, &mean, ops::OperationRelational::More)
+}
+
+// Ordered dithering against a tiled Bayer matrix, keeping tonal gradations
+// as dot density
+fn dither(luma: &VipsImage) -> Result<VipsImage, libvips_rs::error::Error> {
+    let levels: Vec<f64> = BAYER_8.iter().map(|&b| (b as f64 + 0.5) * 4.0).collect();
+    let matrix = VipsImage::image_new_matrix_from_array(8, 8, &levels)?;
+    let (width, height) = (luma.get_width(), luma.get_height());
+    let tiled = ops::replicate(&matrix, (width + 7) / 8, (height + 7) / 8)?;
+    let tiled = ops::extract_area(&tiled, 0, 0, width, height)?;
+    ops::relational(luma, &tiled, ops::OperationRelational::More)
+}
+
+// Runs a custom quality's steps in order on the 8-bit colour bands, leaving
+// any alpha band untouched
+fn custom_steps(img: VipsImage, steps: &[QualityStep]) -> Result<VipsImage, libvips_rs::error::Error> {
+    let img = encode::to_8bit(img)?;
+    let (mut color, alpha) = if img.image_hasalpha() {
+        let color_bands = img.get_bands() - 1;
+        let color = ops::extract_band_with_opts(&img, 0, &ops::ExtractBandOptions { n: color_bands })?;
+        (color, Some(ops::extract_band(&img, color_bands)?))
+    } else {
+        (img, None)
+    };
+
+    for step in steps {
+        color = match *step {
+            QualityStep::Normalize => normalize(&color)?,
+            QualityStep::Equalize => ops::hist_equal(&color)?,
+            QualityStep::Sharpen => ops::sharpen(&color)?,
+            QualityStep::Invert => ops::invert(&color)?,
+            QualityStep::Gray => gray(color)?,
+            // vips_gamma raises to 1 / exponent; invert so values map to x^exponent
+            QualityStep::Gamma(exponent) => {
+                ops::gamma_with_opts(&color, &ops::GammaOptions { exponent: 1.0 / exponent })?
+            }
+            QualityStep::Blur(sigma) => ops::gaussblur(&color, sigma)?,
+        };
+    }
+    match alpha {
+        Some(alpha) => ops::bandjoin(&mut [color, alpha]),
+        None => Ok(color),
+    }
+}
+
+// Stretches the range between the 1st and 99th percentiles of brightness to
+// 0-255, which lifts faded originals without letting outliers dominate
+fn normalize(img: &VipsImage) -> Result<VipsImage, libvips_rs::error::Error> {
+    let counts = histogram(&ops::bandmean(img)?)?;
+    let total: u64 = counts.iter().sum();
+    let percentile = |fraction: f64| {
+        let target = (total as f64 * fraction) as u64;
+        let mut seen = 0;
+        counts
+            .iter()
+            .position(|&n| {
+                seen += n;
+                seen > target
+            })
+            .unwrap_or(255) as f64
+    };
+    let (low, high) = (percentile(0.01), percentile(0.99));
+    if high <= low {
+        return ops::copy(img);
+    }
+
+    let lut: Vec<f64> = (0..256)
+        .map(|i| ((i as f64 - low) * 255.0 / (high - low)).clamp(0.0, 255.0))
+        .collect();
+    let lut = VipsImage::image_new_matrix_from_array(256, 1, &lut)?;
+    let stretched = ops::maplut(img, &lut)?;
+    ops::cast(&stretched, ops::BandFormat::Uchar)
+}
+
+// Pixel counts per level of a one-band 8-bit image
+fn histogram(img: &VipsImage) -> Result<Vec<u64>, libvips_rs::error::Error> {
+    let hist = ops::hist_find(img)?;
+    Ok(hist
+        .image_write_to_memory()
+        .chunks_exact(4)
+        .map(|bytes| u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as u64)
+        .collect())
+}
@@ -0,0 +1,64 @@
+use libvips_rs::{ops, VipsImage};
+use crate::iiif::types::{Format, Rotation};
+use crate::processor::encode;
+
+/// Mirrors and rotates `img` as requested. Multiples of 90 degrees use the
+/// lossless `rot`; other angles fill the exposed corners with transparency
+/// when `format` supports alpha and with `background` otherwise.
+pub fn apply(
+    img: VipsImage,
+    rotation: &Rotation,
+    format: &Format,
+    background: &[f64],
+) -> Result<VipsImage, libvips_rs::error::Error> {
+    let img = if rotation.mirror {
+        ops::flip(&img, ops::Direction::Horizontal)?
+    } else {
+        img
+    };
+
+    let degrees = rotation.degrees % 360.0;
+    if degrees == 0.0 {
+        return Ok(img);
+    }
+    if degrees % 90.0 == 0.0 {
+        let angle = match degrees as u32 {
+            90 => ops::Angle::D90,
+            180 => ops::Angle::D180,
+            _ => ops::Angle::D270,
+        };
+        return ops::rot(&img, angle);
+    }
+
+    let (img, background) = fill(img, format, background)?;
+    ops::rotate_with_opts(&img, degrees, &ops::RotateOptions {
+        background,
+        ..Default::default()
+    })
+}
+
+// Adds an alpha band and a transparent fill where the output can store it,
+// or returns the configured background scaled to the image's depth
+fn fill(
+    img: VipsImage,
+    format: &Format,
+    background: &[f64],
+) -> Result<(VipsImage, Vec<f64>), libvips_rs::error::Error> {
+    let max = if matches!(img.get_format()?, ops::BandFormat::Ushort) { 65535.0 } else { 255.0 };
+    let has_alpha = img.image_hasalpha();
+    let color_bands = img.get_bands() - has_alpha as i32;
+
+    if encode::supports_alpha(format) {
+        let img = if has_alpha { img } else { ops::bandjoin_const(&img, &mut [max])? };
+        return Ok((img, vec![0.0; color_bands as usize + 1]));
+    }
+
+    let mut fill: Vec<f64> = encode::background_for(color_bands, background)
+        .into_iter()
+        .map(|v| v * max / 255.0)
+        .collect();
+    if has_alpha {
+        fill.push(max);
+    }
+    Ok((img, fill))
+}
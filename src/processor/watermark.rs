@@ -0,0 +1,96 @@
+use libvips_rs::{ops, VipsImage};
+use crate::config::{WatermarkConfig, WatermarkPosition};
+use crate::processor::{encode, ImageMetadata};
+
+// Gap between a corner-placed watermark and the image edge, as a fraction
+// of the output width
+const MARGIN: f64 = 0.02;
+
+/// Composites the configured watermark onto `img` when the whole image at
+/// the output's `scale` is larger than the policy's `min_size` in either
+/// dimension, so tiles of a large view are marked like the full view.
+pub fn apply(
+    img: VipsImage,
+    watermark: &WatermarkConfig,
+    meta: &ImageMetadata,
+    scale: (f64, f64),
+) -> Result<VipsImage, libvips_rs::error::Error> {
+    let scaled_width = (meta.width as f64 * scale.0).round();
+    let scaled_height = (meta.height as f64 * scale.1).round();
+    if scaled_width.max(scaled_height) <= watermark.min_size as f64 {
+        return Ok(img);
+    }
+    let (width, height) = (img.get_width(), img.get_height());
+    let overlay = match (&watermark.image, &watermark.text) {
+        (Some(path), _) => image_overlay(path)?,
+        (None, Some(text)) => text_overlay(text, watermark)?,
+        (None, None) => return Ok(img),
+    };
+
+    let target_width = (width as f64 * watermark.scale).max(1.0);
+    let overlay = ops::resize(&overlay, target_width / overlay.get_width() as f64)?;
+    let overlay = fade(overlay, watermark.opacity)?;
+
+    let base = encode::to_8bit(img)?;
+    let (x, y) = place(watermark.position, (width, height), (overlay.get_width(), overlay.get_height()));
+    let gray = base.get_bands() - base.image_hasalpha() as i32 == 1;
+    ops::composite_2_with_opts(&base, &overlay, ops::BlendMode::Over, &ops::Composite2Options {
+        x,
+        y,
+        compositing_space: if gray { ops::Interpretation::BW } else { ops::Interpretation::Srgb },
+        ..Default::default()
+    })
+}
+
+fn image_overlay(path: &str) -> Result<VipsImage, libvips_rs::error::Error> {
+    let img = encode::to_8bit(VipsImage::new_from_file(path)?)?;
+    if img.image_hasalpha() {
+        Ok(img)
+    } else {
+        ops::bandjoin_const(&img, &mut [255.0])
+    }
+}
+
+fn text_overlay(text: &str, watermark: &WatermarkConfig) -> Result<VipsImage, libvips_rs::error::Error> {
+    let markup = format!("<span foreground=\"{}\">{}</span>", escape_markup(&watermark.color), escape_markup(text));
+    ops::text_with_opts(&markup, &ops::TextOptions {
+        font: watermark.font.clone(),
+        rgba: true,
+        ..Default::default()
+    })
+}
+
+// Text is rendered as Pango markup, so config values must not inject tags
+fn escape_markup(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+// Scales the overlay's alpha band by `opacity`
+fn fade(overlay: VipsImage, opacity: f64) -> Result<VipsImage, libvips_rs::error::Error> {
+    let opacity = opacity.clamp(0.0, 1.0);
+    if opacity >= 1.0 {
+        return Ok(overlay);
+    }
+    let color_bands = overlay.get_bands() - 1;
+    let color = ops::extract_band_with_opts(&overlay, 0, &ops::ExtractBandOptions { n: color_bands })?;
+    let alpha = ops::extract_band(&overlay, color_bands)?;
+    let alpha = ops::multiply(&alpha, &VipsImage::new_from_image1(&alpha, opacity)?)?;
+    let alpha = ops::cast(&alpha, ops::BandFormat::Uchar)?;
+    ops::bandjoin(&mut [color, alpha])
+}
+
+fn place(position: WatermarkPosition, (width, height): (i32, i32), (w, h): (i32, i32)) -> (i32, i32) {
+    let margin = (width as f64 * MARGIN) as i32;
+    let (left, top) = (margin, margin);
+    let (right, bottom) = (width - w - margin, height - h - margin);
+    match position {
+        WatermarkPosition::Center => ((width - w) / 2, (height - h) / 2),
+        WatermarkPosition::TopLeft => (left, top),
+        WatermarkPosition::TopRight => (right, top),
+        WatermarkPosition::BottomLeft => (left, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+    }
+}
@@ -58,6 +58,63 @@ impl Resolver {
         None
     }
 
+    /// Fingerprint of the resolved source file, derived from its modification
+    /// time and size. Replacing a master changes its version, so tiles
+    /// rendered from the old file stop matching new cache keys.
+    pub async fn source_version(&self, path: &Path) -> Option<String> {
+        let meta = fs::metadata(path).await.ok()?;
+        let mtime = meta
+            .modified()
+            .ok()?
+            .duration_since(std::time::UNIX_EPOCH)
+            .ok()?;
+        Some(format!("{}.{:09}-{}", mtime.as_secs(), mtime.subsec_nanos(), meta.len()))
+    }
+
+    /// Deletes the locally proxied copy of a remote source. Returns the
+    /// number of files removed.
+    pub async fn purge_proxy(&self, identifier: &str) -> usize {
+        let Some(remote_cfg) = &self.config.remote else {
+            return 0;
+        };
+        let base_id = identifier.split_once(":page:").map_or(identifier, |(base, _)| base);
+        let proxy_path = Path::new(&remote_cfg.local_proxy_dir).join(base_id);
+        match fs::remove_file(&proxy_path).await {
+            Ok(()) => 1,
+            Err(_) => 0,
+        }
+    }
+
+    /// Deletes every proxied file whose identifier starts with `prefix`.
+    pub async fn purge_proxy_prefix(&self, prefix: &str) -> usize {
+        let Some(remote_cfg) = &self.config.remote else {
+            return 0;
+        };
+        let root = PathBuf::from(&remote_cfg.local_proxy_dir);
+        let mut removed = 0;
+        let mut dirs = vec![root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let Ok(mut entries) = fs::read_dir(&dir).await else {
+                continue;
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                let path = entry.path();
+                if entry.file_type().await.is_ok_and(|t| t.is_dir()) {
+                    dirs.push(path);
+                    continue;
+                }
+                let Ok(relative) = path.strip_prefix(&root) else {
+                    continue;
+                };
+                let id = relative.to_string_lossy().replace(std::path::MAIN_SEPARATOR, "/");
+                if id.starts_with(prefix) && fs::remove_file(&path).await.is_ok() {
+                    removed += 1;
+                }
+            }
+        }
+        removed
+    }
+
     async fn fetch_remote(&self, url: &str, dest: &PathBuf) -> Result<(), Box<dyn std::error::Error>> {
         let response = self.client.get(url).send().await?;
         if !response.status().is_success() {